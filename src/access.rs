@@ -0,0 +1,241 @@
+use std::fs::{DirBuilder, Permissions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+
+use smol::net::unix::UnixListener;
+
+use serde::{Deserialize, Serialize};
+
+use crate::client::ClientCommand;
+use crate::config::Config;
+
+/* Allow-lists for local clients, checked against the peer credentials of
+ * the socket. Management permission implies read permission.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AccessConfig {
+    #[serde(rename = "read-uids", default)]
+    pub read_uids: Vec<u32>,
+    #[serde(rename = "read-gids", default)]
+    pub read_gids: Vec<u32>,
+    #[serde(rename = "manage-uids", default)]
+    pub manage_uids: Vec<u32>,
+    #[serde(rename = "manage-gids", default)]
+    pub manage_gids: Vec<u32>,
+}
+
+/* Credentials of the peer as they were when it connected, including its
+ * supplementary groups, so they stay right even if the peer exits.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: Vec<u32>,
+}
+
+impl PeerCredentials {
+    pub fn of<S: AsRawFd>(stream: &S) -> std::io::Result<PeerCredentials> {
+        let mut credentials = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut credentials as *mut libc::ucred as *mut libc::c_void,
+                &mut length,
+            )
+        };
+
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(PeerCredentials {
+            pid: credentials.pid as u32,
+            uid: credentials.uid,
+            gid: credentials.gid,
+            groups: std::iter::once(credentials.gid)
+                .chain(peer_groups(stream)?)
+                .collect(),
+        })
+    }
+}
+
+/* Kernels before 4.13 do not have SO_PEERGROUPS, peers then only get the
+ * access of their primary group.
+ */
+fn peer_groups<S: AsRawFd>(stream: &S) -> std::io::Result<Vec<u32>> {
+    let gid_size = std::mem::size_of::<libc::gid_t>();
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+
+    loop {
+        let mut length = (groups.len() * gid_size) as libc::socklen_t;
+
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERGROUPS,
+                groups.as_mut_ptr() as *mut libc::c_void,
+                &mut length,
+            )
+        };
+        let count = length as usize / gid_size;
+
+        if result == 0 {
+            groups.truncate(count);
+            return Ok(groups);
+        }
+
+        let e = std::io::Error::last_os_error();
+        match e.raw_os_error() {
+            /* The length is set to what the groups need */
+            Some(libc::ERANGE) if count > groups.len() => groups.resize(count, 0),
+            Some(libc::ENOPROTOOPT) => return Ok(vec![]),
+            _ => return Err(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PeerAccess {
+    pub read: bool,
+    pub manage: bool,
+}
+
+impl PeerAccess {
+    pub const READ_ONLY: PeerAccess = PeerAccess {
+        read: true,
+        manage: false,
+    };
+
+    /* Root and the user running the service can always do anything. Without
+     * an `[access]` section anyone who can reach the socket can query, but
+     * only they can manage the service.
+     */
+    pub fn for_local_peer(credentials: &PeerCredentials, config: &Config) -> PeerAccess {
+        let service_uid = unsafe { libc::geteuid() };
+        if credentials.uid == 0 || credentials.uid == service_uid {
+            return PeerAccess {
+                read: true,
+                manage: true,
+            };
+        }
+
+        let Some(access_config) = &config.access else {
+            return PeerAccess::READ_ONLY;
+        };
+
+        let allowed = |uids: &[u32], gids: &[u32]| {
+            uids.contains(&credentials.uid)
+                || credentials.groups.iter().any(|gid| gids.contains(gid))
+        };
+
+        let manage = allowed(&access_config.manage_uids, &access_config.manage_gids);
+        let read = manage || allowed(&access_config.read_uids, &access_config.read_gids);
+
+        PeerAccess { read, manage }
+    }
+
+    pub fn allows(&self, command: &ClientCommand) -> bool {
+        if command.is_management() {
+            self.manage
+        } else {
+            self.read
+        }
+    }
+}
+
+/* Binds in a directory only the service can reach, and links the socket into
+ * place once its mode and owner are set, so that it is never reachable with
+ * the default permissions. Linking fails like binding does if something is
+ * already at the path.
+ */
+pub fn bind_socket(socket_path: &Path, config: &Config) -> anyhow::Result<UnixListener> {
+    let file_name = socket_path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a socket path", socket_path.display()))?;
+    let private_dir = socket_path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let _ = std::fs::remove_dir_all(&private_dir);
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .map_err(|e| anyhow::anyhow!("Could not create {}: {e}", private_dir.display()))?;
+
+    let private_path = private_dir.join(file_name);
+    let listener = UnixListener::bind(&private_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            apply_socket_permissions(&private_path, config)?;
+            std::fs::hard_link(&private_path, socket_path)
+                .map_err(|e| anyhow::anyhow!("Could not bind {}: {e}", socket_path.display()))?;
+            Ok(listener)
+        });
+
+    let _ = std::fs::remove_dir_all(&private_dir);
+    listener
+}
+
+/* Applies the configured mode and ownership to a freshly bound socket */
+fn apply_socket_permissions(socket_path: &Path, config: &Config) -> anyhow::Result<()> {
+    if let Some(mode) = config.socket_mode {
+        std::fs::set_permissions(socket_path, Permissions::from_mode(mode)).map_err(|e| {
+            anyhow::anyhow!(
+                "Could not set the mode of {} to {mode:o}: {e}",
+                socket_path.display()
+            )
+        })?;
+    }
+
+    if config.socket_owner.is_some() || config.socket_group.is_some() {
+        std::os::unix::fs::chown(socket_path, config.socket_owner, config.socket_group).map_err(
+            |e| {
+                anyhow::anyhow!(
+                    "Could not change the owner of {}: {e}",
+                    socket_path.display()
+                )
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn peer_credentials_include_supplementary_groups() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        drop(theirs);
+
+        let mut expected = vec![0; 256];
+        let count =
+            unsafe { libc::getgroups(expected.len() as libc::c_int, expected.as_mut_ptr()) };
+        expected.truncate(count as usize);
+
+        let credentials = PeerCredentials::of(&ours).unwrap();
+        assert_eq!(credentials.uid, unsafe { libc::geteuid() });
+        assert_eq!(credentials.gid, unsafe { libc::getegid() });
+        for gid in expected {
+            assert!(credentials.groups.contains(&gid), "missing group {gid}");
+        }
+    }
+}
@@ -0,0 +1,657 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::monitor::SelectedTarget;
+use crate::notify::MONITOR_DOWN_RULE;
+use crate::ping::PingReading;
+use crate::stats::{PingStatistics, TimeRange};
+
+/* Alert history is kept in memory, the oldest transitions are dropped past
+ * this.
+ */
+pub const MAX_ALERT_HISTORY: usize = 1000;
+
+const DEFAULT_WINDOW_SECONDS: f32 = 60.0;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlertMetric {
+    Latency,
+    Loss,
+    Jitter,
+}
+
+impl AlertMetric {
+    /* Latency is the median over the window so that a single slow reply
+     * does not fire an alert on its own.
+     */
+    fn value(&self, statistics: &PingStatistics) -> f64 {
+        match self {
+            AlertMetric::Latency => statistics.median_ms,
+            AlertMetric::Loss => statistics.loss_percent,
+            AlertMetric::Jitter => statistics.jitter_ms,
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            AlertMetric::Latency | AlertMetric::Jitter => "ms",
+            AlertMetric::Loss => "%",
+        }
+    }
+}
+
+impl Display for AlertMetric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AlertMetric::Latency => "latency",
+            AlertMetric::Loss => "loss",
+            AlertMetric::Jitter => "jitter",
+        })
+    }
+}
+
+fn default_window_seconds() -> f32 {
+    DEFAULT_WINDOW_SECONDS
+}
+
+/* An alert fires once the metric has been over `fire-above` for
+ * `for-seconds`, and resolves once it has been under `resolve-below` for
+ * `resolve-after-seconds`. Leaving a gap between the two keeps an alert from
+ * flapping around a single threshold. Targets are matched by name, where `*`
+ * matches anything, and an empty list matches every target.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    #[serde(default)]
+    pub targets: Vec<String>,
+    pub metric: AlertMetric,
+    #[serde(rename = "fire-above")]
+    pub fire_above: f64,
+    #[serde(rename = "resolve-below")]
+    pub resolve_below: Option<f64>,
+    #[serde(rename = "window-seconds", default = "default_window_seconds")]
+    pub window_seconds: f32,
+    #[serde(rename = "for-seconds", default)]
+    pub for_seconds: f32,
+    #[serde(rename = "resolve-after-seconds")]
+    pub resolve_after_seconds: Option<f32>,
+}
+
+fn seconds(name: &str, seconds: f32) -> Result<Duration, String> {
+    Duration::try_from_secs_f32(seconds)
+        .map_err(|_| format!("{name} must be a non-negative number of seconds, not {seconds}"))
+}
+
+/* Matches a target against a selector where `*` stands for any characters */
+pub fn selector_matches(selector: &str, target: &str) -> bool {
+    let mut parts = selector.split('*');
+    let Some(mut rest) = target.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+impl AlertRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.fire_above.is_finite() {
+            return Err(format!(
+                "fire-above must be a number, not {}",
+                self.fire_above
+            ));
+        }
+
+        let resolve_threshold = self.resolve_threshold();
+        if !resolve_threshold.is_finite() || resolve_threshold > self.fire_above {
+            return Err(format!(
+                "resolve-below must be a number no higher than fire-above, not {resolve_threshold}"
+            ));
+        }
+
+        if self.window()?.is_zero() {
+            return Err(String::from("window-seconds must be more than 0"));
+        }
+        self.fire_after()?;
+        self.resolve_after()?;
+
+        if self.targets.iter().any(String::is_empty) {
+            return Err(String::from("target selectors must not be empty"));
+        }
+
+        Ok(())
+    }
+
+    pub fn matches(&self, target: &str) -> bool {
+        self.targets.is_empty()
+            || self
+                .targets
+                .iter()
+                .any(|selector| selector_matches(selector, target))
+    }
+
+    pub fn resolve_threshold(&self) -> f64 {
+        self.resolve_below.unwrap_or(self.fire_above)
+    }
+
+    fn window(&self) -> Result<Duration, String> {
+        seconds("window-seconds", self.window_seconds)
+    }
+
+    fn fire_after(&self) -> Result<Duration, String> {
+        seconds("for-seconds", self.for_seconds)
+    }
+
+    fn resolve_after(&self) -> Result<Duration, String> {
+        seconds(
+            "resolve-after-seconds",
+            self.resolve_after_seconds.unwrap_or(self.for_seconds),
+        )
+    }
+}
+
+pub fn validate_alert_rules(rules: &HashMap<String, AlertRule>) -> Result<(), String> {
+    for (name, rule) in rules {
+        if name == MONITOR_DOWN_RULE {
+            return Err(format!(
+                "alert {name}: the name is reserved for targets that stop producing readings"
+            ));
+        }
+        rule.validate().map_err(|e| format!("alert {name}: {e}"))?;
+    }
+
+    Ok(())
+}
+
+/* Alerts that are not in any of these states are not kept */
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlertState {
+    Pending,
+    Firing,
+    Resolving,
+}
+
+impl Display for AlertState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AlertState::Pending => "pending",
+            AlertState::Firing => "firing",
+            AlertState::Resolving => "resolving",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ActiveAlert {
+    pub rule: String,
+    pub target: String,
+    pub metric: AlertMetric,
+    pub state: AlertState,
+    pub state_since: SystemTime,
+    pub fired_at: Option<SystemTime>,
+    pub value: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlertTransition {
+    Fired,
+    Resolved,
+}
+
+impl Display for AlertTransition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AlertTransition::Fired => "fired",
+            AlertTransition::Resolved => "resolved",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertEvent {
+    pub rule: String,
+    pub target: String,
+    pub metric: AlertMetric,
+    pub transition: AlertTransition,
+    pub time: SystemTime,
+    pub value: f64,
+    pub threshold: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertReport {
+    pub active: Vec<ActiveAlert>,
+    pub history: Vec<AlertEvent>,
+}
+
+/* Keeps the state of every rule for every target it matches, along with the
+ * alerts that fired and resolved. Alerts resolved because their rule changed
+ * are kept in `dropped` until the next evaluation, which reports them.
+ */
+#[derive(Debug, Default)]
+pub struct AlertEngine {
+    rules: HashMap<String, AlertRule>,
+    active: HashMap<(String, String), ActiveAlert>,
+    history: VecDeque<AlertEvent>,
+    dropped: Vec<AlertEvent>,
+}
+
+/* An alert that stops being evaluated resolves if it had fired, so that
+ * whoever was notified of it is told it is over.
+ */
+fn resolve_dropped(alert: ActiveAlert, rule: &AlertRule, now: SystemTime) -> Option<AlertEvent> {
+    alert.fired_at?;
+    log::info!(
+        "Alert {} resolved for {}, it is no longer evaluated",
+        alert.rule,
+        alert.target
+    );
+
+    Some(AlertEvent {
+        rule: alert.rule,
+        target: alert.target,
+        metric: alert.metric,
+        transition: AlertTransition::Resolved,
+        time: now,
+        value: alert.value,
+        threshold: rule.resolve_threshold(),
+    })
+}
+
+impl AlertEngine {
+    /* Alerts of rules that are unchanged keep their state, the others start
+     * over, resolving if they had fired.
+     */
+    pub fn set_rules(&mut self, rules: HashMap<String, AlertRule>) {
+        let now = SystemTime::now();
+        let changed: Vec<(String, String)> = self
+            .active
+            .keys()
+            .filter(|(rule, _)| rules.get(rule) != self.rules.get(rule))
+            .cloned()
+            .collect();
+        for key in changed {
+            if let (Some(alert), Some(rule)) = (self.active.remove(&key), self.rules.get(&key.0)) {
+                self.dropped.extend(resolve_dropped(alert, rule, now));
+            }
+        }
+
+        self.rules = rules;
+    }
+
+    pub fn rules(&self) -> &HashMap<String, AlertRule> {
+        &self.rules
+    }
+
+    /* Returns the alerts that fired or resolved */
+    pub fn evaluate(&mut self, targets: &[SelectedTarget], now: SystemTime) -> Vec<AlertEvent> {
+        let mut events = std::mem::take(&mut self.dropped);
+
+        let monitored: HashSet<&String> = targets.iter().map(|selected| &selected.target).collect();
+        let removed: Vec<(String, String)> = self
+            .active
+            .keys()
+            .filter(|(_, target)| !monitored.contains(target))
+            .cloned()
+            .collect();
+        for key in removed {
+            if let (Some(alert), Some(rule)) = (self.active.remove(&key), self.rules.get(&key.0)) {
+                events.extend(resolve_dropped(alert, rule, now));
+            }
+        }
+
+        for (name, rule) in &self.rules {
+            let Ok(window) = rule.window() else {
+                continue;
+            };
+            let window_start = now.checked_sub(window).unwrap_or(SystemTime::UNIX_EPOCH);
+
+            for selected in targets
+                .iter()
+                .filter(|selected| rule.matches(&selected.target))
+            {
+                let readings: Vec<Arc<PingReading>> = selected
+                    .reading_history
+                    .lock()
+                    .unwrap()
+                    .readings_since(window_start);
+                let readings: Vec<&PingReading> = readings.iter().map(Arc::as_ref).collect();
+
+                /* A target that has been pinged for the whole window
+                 * without a reading lost every ping. Otherwise there is
+                 * nothing to decide on, so the alert is left as it is.
+                 */
+                let value = match PingStatistics::from_readings(&readings) {
+                    Some(statistics) => rule.metric.value(&statistics),
+                    None if rule.metric == AlertMetric::Loss
+                        && selected
+                            .active_since
+                            .is_some_and(|active_since| active_since <= window_start) =>
+                    {
+                        100.0
+                    }
+                    None => continue,
+                };
+
+                let key = (name.clone(), selected.target.clone());
+                events.extend(Self::step(&mut self.active, key, rule, value, now));
+            }
+        }
+
+        for event in &events {
+            self.history.push_back(event.clone());
+        }
+        while self.history.len() > MAX_ALERT_HISTORY {
+            self.history.pop_front();
+        }
+
+        events
+    }
+
+    fn step(
+        active: &mut HashMap<(String, String), ActiveAlert>,
+        key: (String, String),
+        rule: &AlertRule,
+        value: f64,
+        now: SystemTime,
+    ) -> Option<AlertEvent> {
+        let fires = value > rule.fire_above;
+        let resolves = value < rule.resolve_threshold();
+
+        let alert = match active.get_mut(&key) {
+            Some(alert) => alert,
+            None if fires => active.entry(key.clone()).or_insert(ActiveAlert {
+                rule: key.0.clone(),
+                target: key.1.clone(),
+                metric: rule.metric,
+                state: AlertState::Pending,
+                state_since: now,
+                fired_at: None,
+                value,
+            }),
+            None => return None,
+        };
+        alert.value = value;
+
+        let next_state = match alert.state {
+            AlertState::Pending if !fires => None,
+            AlertState::Firing if resolves => Some(AlertState::Resolving),
+            AlertState::Resolving if !resolves => Some(AlertState::Firing),
+            state => Some(state),
+        };
+
+        let Some(next_state) = next_state else {
+            active.remove(&key);
+            return None;
+        };
+        if next_state != alert.state {
+            alert.state = next_state;
+            alert.state_since = now;
+        }
+
+        let in_state_for = now.duration_since(alert.state_since).unwrap_or_default();
+        let (transition, threshold) = match alert.state {
+            AlertState::Pending if in_state_for >= rule.fire_after().ok()? => {
+                alert.state = AlertState::Firing;
+                alert.state_since = now;
+                alert.fired_at = Some(now);
+                (AlertTransition::Fired, rule.fire_above)
+            }
+            AlertState::Resolving if in_state_for >= rule.resolve_after().ok()? => {
+                active.remove(&key);
+                (AlertTransition::Resolved, rule.resolve_threshold())
+            }
+            _ => return None,
+        };
+
+        let (rule_name, target) = key;
+        match transition {
+            AlertTransition::Fired => log::warn!(
+                "Alert {rule_name} fired for {target}, {} is {value:.1} {}",
+                rule.metric,
+                rule.metric.unit()
+            ),
+            AlertTransition::Resolved => log::info!(
+                "Alert {rule_name} resolved for {target}, {} is {value:.1} {}",
+                rule.metric,
+                rule.metric.unit()
+            ),
+        }
+
+        Some(AlertEvent {
+            rule: rule_name,
+            target,
+            metric: rule.metric,
+            transition,
+            time: now,
+            value,
+            threshold,
+        })
+    }
+
+    pub fn report(&self, target: &Option<String>, range: &TimeRange) -> AlertReport {
+        let selected = |alert_target: &String| target.as_ref().is_none_or(|t| t == alert_target);
+
+        let mut active: Vec<ActiveAlert> = self
+            .active
+            .values()
+            .filter(|alert| selected(&alert.target))
+            .cloned()
+            .collect();
+        active.sort_by(|a, b| (&a.rule, &a.target).cmp(&(&b.rule, &b.target)));
+
+        AlertReport {
+            active,
+            history: self
+                .history
+                .iter()
+                .filter(|event| selected(&event.target) && range.contains(event.time))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::anomaly::AnomalyConfig;
+    use crate::config::PingMonitorConfig;
+    use crate::ping::PingReadingHistory;
+
+    fn monitor_config() -> PingMonitorConfig {
+        PingMonitorConfig {
+            interval_seconds: 1.0,
+            history_length_hours: 1.0,
+            sla: None,
+            anomaly: AnomalyConfig::default(),
+        }
+    }
+
+    fn rule(metric: AlertMetric) -> AlertRule {
+        AlertRule {
+            targets: vec![],
+            metric,
+            fire_above: 100.0,
+            resolve_below: Some(50.0),
+            window_seconds: 60.0,
+            for_seconds: 10.0,
+            resolve_after_seconds: Some(10.0),
+        }
+    }
+
+    fn engine(rule: AlertRule) -> AlertEngine {
+        let mut engine = AlertEngine::default();
+        engine.set_rules(HashMap::from([("test".to_string(), rule)]));
+        engine
+    }
+
+    /* Readings are timestamped when they are added, so they are in the
+     * window of an evaluation shortly after.
+     */
+    fn target(latencies_ms: &[u64], active_since: Option<SystemTime>) -> SelectedTarget {
+        let config = monitor_config();
+        let mut history = PingReadingHistory::new(
+            config.interval_seconds,
+            Duration::from_secs(60 * 60),
+            config.anomaly,
+        );
+        for (sequence, latency_ms) in latencies_ms.iter().enumerate() {
+            history.add_output_line(&format!(
+                "64 bytes from 192.0.2.1: icmp_seq={sequence} ttl=64 time={latency_ms} ms"
+            ));
+        }
+
+        SelectedTarget {
+            target: "example.test".to_string(),
+            config,
+            reading_history: Arc::new(Mutex::new(history)),
+            active_since,
+        }
+    }
+
+    fn transitions(events: &[AlertEvent]) -> Vec<AlertTransition> {
+        events.iter().map(|event| event.transition).collect()
+    }
+
+    fn state(engine: &AlertEngine) -> Option<AlertState> {
+        engine
+            .report(&None, &TimeRange::default())
+            .active
+            .first()
+            .map(|alert| alert.state)
+    }
+
+    #[test]
+    fn alert_fires_holds_between_thresholds_and_resolves() {
+        let start = SystemTime::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let mut engine = engine(rule(AlertMetric::Latency));
+        let slow = [target(&[200], Some(start))];
+        let between = [target(&[70], Some(start))];
+        let fast = [target(&[10], Some(start))];
+
+        assert!(engine.evaluate(&slow, at(0)).is_empty());
+        assert_eq!(state(&engine), Some(AlertState::Pending));
+        assert!(engine.evaluate(&slow, at(5)).is_empty());
+        assert_eq!(
+            transitions(&engine.evaluate(&slow, at(10))),
+            [AlertTransition::Fired]
+        );
+
+        /* Under fire-above but over resolve-below keeps it firing */
+        assert!(engine.evaluate(&between, at(20)).is_empty());
+        assert_eq!(state(&engine), Some(AlertState::Firing));
+
+        assert!(engine.evaluate(&fast, at(25)).is_empty());
+        assert_eq!(state(&engine), Some(AlertState::Resolving));
+        assert!(engine.evaluate(&slow, at(30)).is_empty());
+        assert_eq!(state(&engine), Some(AlertState::Firing));
+
+        assert!(engine.evaluate(&fast, at(35)).is_empty());
+        let events = engine.evaluate(&fast, at(45));
+        assert_eq!(transitions(&events), [AlertTransition::Resolved]);
+        assert_eq!(events[0].threshold, 50.0);
+        assert_eq!(state(&engine), None);
+    }
+
+    #[test]
+    fn pending_alert_that_recovers_never_fires() {
+        let start = SystemTime::now();
+        let mut engine = engine(rule(AlertMetric::Latency));
+
+        assert!(engine
+            .evaluate(&[target(&[200], Some(start))], start)
+            .is_empty());
+        assert!(engine
+            .evaluate(
+                &[target(&[10], Some(start))],
+                start + Duration::from_secs(5)
+            )
+            .is_empty());
+        assert_eq!(state(&engine), None);
+        assert!(engine.history.is_empty());
+    }
+
+    #[test]
+    fn firing_alert_resolves_when_its_target_is_removed() {
+        let start = SystemTime::now();
+        let mut engine = engine(rule(AlertMetric::Latency));
+        let slow = [target(&[200], Some(start))];
+
+        engine.evaluate(&slow, start);
+        engine.evaluate(&slow, start + Duration::from_secs(10));
+
+        let events = engine.evaluate(&[], start + Duration::from_secs(15));
+        assert_eq!(transitions(&events), [AlertTransition::Resolved]);
+        assert_eq!(state(&engine), None);
+    }
+
+    #[test]
+    fn firing_alert_resolves_when_its_rule_changes() {
+        let start = SystemTime::now();
+        let mut engine = engine(rule(AlertMetric::Latency));
+        let slow = [target(&[200], Some(start))];
+
+        engine.evaluate(&slow, start);
+        engine.evaluate(&slow, start + Duration::from_secs(10));
+
+        /* An unchanged rule keeps its alerts */
+        engine.set_rules(engine.rules.clone());
+        assert_eq!(state(&engine), Some(AlertState::Firing));
+
+        let changed = AlertRule {
+            fire_above: 150.0,
+            ..rule(AlertMetric::Latency)
+        };
+        engine.set_rules(HashMap::from([("test".to_string(), changed)]));
+        assert_eq!(state(&engine), None);
+
+        let events = engine.evaluate(&[], start + Duration::from_secs(15));
+        assert_eq!(transitions(&events), [AlertTransition::Resolved]);
+        assert_eq!(events[0].threshold, 50.0);
+    }
+
+    #[test]
+    fn window_without_readings_is_total_loss() {
+        let start = SystemTime::now();
+        let mut engine = engine(AlertRule {
+            fire_above: 50.0,
+            for_seconds: 0.0,
+            ..rule(AlertMetric::Loss)
+        });
+        let long_ago = start - Duration::from_secs(120);
+
+        /* Targets that are paused or started within the window are not
+         * expected to have readings yet.
+         */
+        assert!(engine.evaluate(&[target(&[], None)], start).is_empty());
+        assert!(engine
+            .evaluate(&[target(&[], Some(start))], start)
+            .is_empty());
+
+        let events = engine.evaluate(&[target(&[], Some(long_ago))], start);
+        assert_eq!(transitions(&events), [AlertTransition::Fired]);
+        assert_eq!(events[0].value, 100.0);
+    }
+}
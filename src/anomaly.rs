@@ -1 +1,613 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::time::SystemTime;
 
+use serde::{Deserialize, Serialize};
+
+use crate::changepoint::{LevelShift, LevelShiftDetector};
+use crate::ping::PingReading;
+use crate::seasonal::SeasonalBaseline;
+use crate::stats::TimeRange;
+
+/* Events are kept per target, the oldest are dropped past this */
+pub const MAX_ANOMALY_EVENTS: usize = 1000;
+
+/* Latency that barely varies would otherwise make every small wobble look
+ * like many deviations.
+ */
+const MIN_LATENCY_SPREAD_MS: f64 = 1.0;
+const MIN_RELATIVE_LATENCY_SPREAD: f64 = 0.05;
+
+/* Recent loss is averaged over about this many packets */
+const RECENT_LOSS_PACKETS: f64 = 10.0;
+const MIN_LOSS_SPREAD: f64 = 0.02;
+const MIN_LOSS_INCREASE: f64 = 0.05;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(default)]
+pub struct AnomalyConfig {
+    /* How many deviations from the baseline count as anomalous, higher
+     * flags less.
+     */
+    pub sensitivity: f64,
+    #[serde(rename = "baseline-readings")]
+    pub baseline_readings: u32,
+    #[serde(rename = "min-anomalous-readings")]
+    pub min_anomalous_readings: u32,
+    /* How many deviations latency has to add up to past its previous level
+     * before it counts as having shifted, higher needs a longer change.
+     */
+    #[serde(rename = "level-shift-threshold")]
+    pub level_shift_threshold: f64,
+    #[serde(rename = "min-level-shift-ms")]
+    pub min_level_shift_ms: f64,
+    /* Flags latency over this many times the usual for the hour of the week
+     * instead, once that hour has been seen.
+     */
+    #[serde(rename = "relative-threshold")]
+    pub relative_threshold: Option<f64>,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            sensitivity: 4.0,
+            baseline_readings: 100,
+            min_anomalous_readings: 3,
+            level_shift_threshold: 40.0,
+            min_level_shift_ms: 5.0,
+            relative_threshold: None,
+        }
+    }
+}
+
+impl AnomalyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.sensitivity.is_finite() || self.sensitivity <= 0.0 {
+            return Err(format!(
+                "anomaly sensitivity must be a positive number, not {}",
+                self.sensitivity
+            ));
+        }
+
+        if self.baseline_readings == 0 || self.min_anomalous_readings == 0 {
+            return Err(String::from(
+                "anomaly baseline and minimum readings must be at least 1",
+            ));
+        }
+
+        if !self.level_shift_threshold.is_finite() || self.level_shift_threshold <= 0.0 {
+            return Err(format!(
+                "level shift threshold must be a positive number, not {}",
+                self.level_shift_threshold
+            ));
+        }
+
+        if !self.min_level_shift_ms.is_finite() || self.min_level_shift_ms < 0.0 {
+            return Err(format!(
+                "minimum level shift must not be negative, not {}",
+                self.min_level_shift_ms
+            ));
+        }
+
+        if let Some(relative_threshold) = self.relative_threshold {
+            if !relative_threshold.is_finite() || relative_threshold <= 1.0 {
+                return Err(format!(
+                    "relative threshold must be a number greater than 1, not {relative_threshold}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /* Smoothing factor of an exponentially weighted average with about the
+     * same memory as a moving average over `baseline_readings`.
+     */
+    fn alpha(&self) -> f64 {
+        2.0 / (self.baseline_readings as f64 + 1.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnomalyKind {
+    HighLatency,
+    HighLoss,
+}
+
+impl Display for AnomalyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AnomalyKind::HighLatency => "high-latency",
+            AnomalyKind::HighLoss => "high-loss",
+        })
+    }
+}
+
+/* Baselines and peaks are in ms for latency and percent for loss. Events
+ * without an end are still ongoing. Relative events are measured against the
+ * usual latency for the hour of the week, their scores are how many times the
+ * usual latency it was rather than deviations.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnomalyEvent {
+    pub kind: AnomalyKind,
+    pub start: SystemTime,
+    pub end: Option<SystemTime>,
+    pub baseline: f64,
+    pub peak: f64,
+    pub peak_score: f64,
+    pub readings: usize,
+    pub relative: bool,
+}
+
+impl AnomalyEvent {
+    fn overlaps(&self, range: &TimeRange) -> bool {
+        range.end.is_none_or(|end| self.start <= end)
+            && range
+                .start
+                .is_none_or(|start| self.end.is_none_or(|event_end| event_end >= start))
+    }
+}
+
+/* A reading as seen by one of the detectors */
+struct Observation {
+    time: SystemTime,
+    value: f64,
+    baseline: f64,
+    score: f64,
+    anomalous: bool,
+    relative: bool,
+}
+
+/* Opens an event after enough anomalous readings in a row and closes it
+ * after as many normal ones.
+ */
+#[derive(Debug, Default)]
+struct EventTracker {
+    anomalous_streak: Vec<(SystemTime, f64, f64)>,
+    normal_streak: u32,
+    open_event: Option<AnomalyEvent>,
+}
+
+impl EventTracker {
+    /* Returns an event once it has ended */
+    fn observe(
+        &mut self,
+        kind: AnomalyKind,
+        observation: Observation,
+        config: &AnomalyConfig,
+    ) -> Option<AnomalyEvent> {
+        let Observation {
+            time,
+            value,
+            baseline,
+            score,
+            anomalous,
+            relative,
+        } = observation;
+
+        if !anomalous {
+            self.anomalous_streak.clear();
+            self.normal_streak += 1;
+
+            if self.normal_streak >= config.min_anomalous_readings {
+                return self.open_event.take();
+            }
+            return None;
+        }
+
+        self.normal_streak = 0;
+
+        if let Some(event) = &mut self.open_event {
+            event.end = Some(time);
+            event.readings += 1;
+            if score > event.peak_score {
+                event.peak = value;
+                event.peak_score = score;
+            }
+            return None;
+        }
+
+        self.anomalous_streak.push((time, value, score));
+        if self.anomalous_streak.len() >= config.min_anomalous_readings as usize {
+            let (start, _, _) = self.anomalous_streak[0];
+            let (peak, peak_score) = self
+                .anomalous_streak
+                .iter()
+                .map(|(_, value, score)| (*value, *score))
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .unwrap_or((value, score));
+
+            self.open_event = Some(AnomalyEvent {
+                kind,
+                start,
+                end: Some(time),
+                baseline,
+                peak,
+                peak_score,
+                readings: self.anomalous_streak.len(),
+                relative,
+            });
+            self.anomalous_streak.clear();
+        }
+
+        None
+    }
+
+    fn ongoing(&self) -> Option<AnomalyEvent> {
+        self.open_event
+            .clone()
+            .map(|event| AnomalyEvent { end: None, ..event })
+    }
+}
+
+/* Learns a baseline of latency and loss from every reading as it arrives,
+ * using exponentially weighted averages. Latency is clipped before it is
+ * learned so that a spike does not drag the baseline along with it.
+ */
+#[derive(Debug)]
+pub struct AnomalyDetector {
+    config: AnomalyConfig,
+    latency_mean: f64,
+    latency_variance: f64,
+    latency_readings: u64,
+    loss_baseline: f64,
+    loss_recent: f64,
+    packets: u64,
+    previous_sequence: Option<u64>,
+    latency_events: EventTracker,
+    loss_events: EventTracker,
+    events: VecDeque<AnomalyEvent>,
+    level_shifts: LevelShiftDetector,
+    seasonal: SeasonalBaseline,
+}
+
+impl AnomalyDetector {
+    pub fn new(config: AnomalyConfig) -> AnomalyDetector {
+        AnomalyDetector {
+            config,
+            latency_mean: 0.0,
+            latency_variance: 0.0,
+            latency_readings: 0,
+            loss_baseline: 0.0,
+            loss_recent: 0.0,
+            packets: 0,
+            previous_sequence: None,
+            latency_events: EventTracker::default(),
+            loss_events: EventTracker::default(),
+            events: VecDeque::new(),
+            level_shifts: LevelShiftDetector::default(),
+            seasonal: SeasonalBaseline::default(),
+        }
+    }
+
+    /* The learned baseline is kept */
+    pub fn set_config(&mut self, config: AnomalyConfig) {
+        self.config = config;
+    }
+
+    fn warmed_up(&self) -> bool {
+        self.latency_readings >= self.config.baseline_readings as u64
+    }
+
+    fn latency_spread(&self) -> f64 {
+        self.latency_variance
+            .sqrt()
+            .max(MIN_LATENCY_SPREAD_MS)
+            .max(self.latency_mean * MIN_RELATIVE_LATENCY_SPREAD)
+    }
+
+    fn observe_latency(&mut self, reading: &PingReading) {
+        let latency = reading.latency.as_secs_f64() * 1000.0;
+        let baseline = self.latency_mean;
+        let spread = self.latency_spread();
+        let usual = self.seasonal.usual_ms(reading.timestamp);
+        self.seasonal.observe(reading.timestamp, latency);
+
+        let observation = match (self.config.relative_threshold, usual) {
+            (Some(relative_threshold), Some(usual)) => {
+                /* Readings are whole ms, so the usual latency can be 0 */
+                let score = latency / usual.max(MIN_LATENCY_SPREAD_MS);
+                Observation {
+                    time: reading.timestamp,
+                    value: latency,
+                    baseline: usual,
+                    score,
+                    anomalous: score > relative_threshold,
+                    relative: true,
+                }
+            }
+            _ => {
+                let score = (latency - baseline) / spread;
+                Observation {
+                    time: reading.timestamp,
+                    value: latency,
+                    baseline,
+                    score,
+                    anomalous: self.warmed_up() && score > self.config.sensitivity,
+                    relative: false,
+                }
+            }
+        };
+
+        let ended =
+            self.latency_events
+                .observe(AnomalyKind::HighLatency, observation, &self.config);
+        self.record(ended);
+
+        let learned_baseline = self.warmed_up().then_some((baseline, spread));
+        if let Some(level) =
+            self.level_shifts
+                .observe(reading.timestamp, latency, learned_baseline, &self.config)
+        {
+            /* The new level is normal from now on rather than a long spike */
+            self.latency_mean = level;
+        }
+
+        /* Averages all readings until there are enough for the baseline */
+        self.latency_readings += 1;
+        let alpha = self.config.alpha().max(1.0 / self.latency_readings as f64);
+        let learned = if self.warmed_up() {
+            latency.clamp(
+                baseline - self.config.sensitivity * spread,
+                baseline + self.config.sensitivity * spread,
+            )
+        } else {
+            latency
+        };
+
+        let difference = learned - self.latency_mean;
+        self.latency_mean += alpha * difference;
+        self.latency_variance =
+            (1.0 - alpha) * (self.latency_variance + alpha * difference.powi(2));
+    }
+
+    fn loss_spread(&self) -> f64 {
+        let recent_alpha = 2.0 / (RECENT_LOSS_PACKETS + 1.0);
+        (self.loss_baseline * (1.0 - self.loss_baseline) * recent_alpha / (2.0 - recent_alpha))
+            .sqrt()
+            .max(MIN_LOSS_SPREAD)
+    }
+
+    fn loss_is_anomalous(&self, increase: f64, score: f64) -> bool {
+        self.warmed_up() && score > self.config.sensitivity && increase >= MIN_LOSS_INCREASE
+    }
+
+    fn observe_packet(&mut self, lost: bool) {
+        let sample = if lost { 1.0 } else { 0.0 };
+
+        self.packets += 1;
+        let recent_alpha = (2.0 / (RECENT_LOSS_PACKETS + 1.0)).max(1.0 / self.packets as f64);
+        self.loss_recent += recent_alpha * (sample - self.loss_recent);
+
+        /* Loss that already looks anomalous is learned slowly, so that a
+         * lasting change is eventually accepted without hiding a short one.
+         */
+        let increase = self.loss_recent - self.loss_baseline;
+        let mut baseline_alpha = self.config.alpha().max(1.0 / self.packets as f64);
+        if self.loss_events.open_event.is_some()
+            || self.loss_is_anomalous(increase, increase / self.loss_spread())
+        {
+            baseline_alpha /= 10.0;
+        }
+        self.loss_baseline += baseline_alpha * (sample - self.loss_baseline);
+    }
+
+    fn observe_loss(&mut self, reading: &PingReading) {
+        /* The sequence going backwards means ping was restarted */
+        if let (Some(previous), Some(current)) = (self.previous_sequence, reading.sequence) {
+            if current > previous {
+                for _ in 0..(current - previous - 1).min(self.config.baseline_readings as u64) {
+                    self.observe_packet(true);
+                }
+            }
+        }
+        self.previous_sequence = reading.sequence;
+        self.observe_packet(false);
+
+        let baseline = self.loss_baseline;
+        let increase = self.loss_recent - baseline;
+        let score = increase / self.loss_spread();
+        let anomalous = self.loss_is_anomalous(increase, score);
+
+        let ended = self.loss_events.observe(
+            AnomalyKind::HighLoss,
+            Observation {
+                time: reading.timestamp,
+                value: self.loss_recent * 100.0,
+                baseline: baseline * 100.0,
+                score,
+                anomalous,
+                relative: false,
+            },
+            &self.config,
+        );
+        self.record(ended);
+    }
+
+    fn record(&mut self, event: Option<AnomalyEvent>) {
+        if let Some(event) = event {
+            self.events.push_back(event);
+
+            while self.events.len() > MAX_ANOMALY_EVENTS {
+                self.events.pop_front();
+            }
+        }
+    }
+
+    pub fn observe(&mut self, reading: &PingReading) {
+        self.observe_latency(reading);
+        self.observe_loss(reading);
+    }
+
+    pub fn seasonal_baseline(&self) -> &SeasonalBaseline {
+        &self.seasonal
+    }
+
+    pub fn seasonal_baseline_mut(&mut self) -> &mut SeasonalBaseline {
+        &mut self.seasonal
+    }
+
+    pub fn level_shifts(&self, range: &TimeRange) -> Vec<LevelShift> {
+        self.level_shifts.shifts(range)
+    }
+
+    pub fn events(&self, range: &TimeRange) -> Vec<AnomalyEvent> {
+        self.events
+            .iter()
+            .cloned()
+            .chain(self.latency_events.ongoing())
+            .chain(self.loss_events.ongoing())
+            .filter(|event| event.overlaps(range))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /* A monday in january, away from daylight saving changes */
+    const START_SECONDS: u64 = 1_673_222_400;
+
+    fn reading(seconds: u64, sequence: u64, latency_ms: u64) -> PingReading {
+        PingReading {
+            latency: Duration::from_millis(latency_ms),
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(START_SECONDS + seconds),
+            sequence: Some(sequence),
+            original_line: String::new(),
+        }
+    }
+
+    /* Feeds one reading a second from `from`, returns where it stopped */
+    fn observe(detector: &mut AnomalyDetector, from: u64, latencies_ms: &[u64]) -> u64 {
+        for (i, latency_ms) in latencies_ms.iter().enumerate() {
+            let index = from + i as u64;
+            detector.observe(&reading(index, index, *latency_ms));
+        }
+        from + latencies_ms.len() as u64
+    }
+
+    /* Alternates by 2 ms, a spread of about 1 ms */
+    fn steady(count: usize) -> Vec<u64> {
+        (0..count).map(|i| 10 + 2 * (i as u64 % 2)).collect()
+    }
+
+    fn events(detector: &AnomalyDetector) -> Vec<AnomalyEvent> {
+        detector.events(&TimeRange::default())
+    }
+
+    #[test]
+    fn latency_spike_after_warm_up_is_an_event() {
+        let mut detector = AnomalyDetector::new(AnomalyConfig::default());
+        let next = observe(&mut detector, 0, &steady(100));
+        let next = observe(&mut detector, next, &[100, 80, 90, 60, 70]);
+
+        let ongoing = events(&detector);
+        assert_eq!(ongoing.len(), 1);
+        assert_eq!(ongoing[0].kind, AnomalyKind::HighLatency);
+        assert_eq!(ongoing[0].end, None);
+        assert_eq!(ongoing[0].readings, 5);
+        assert_eq!(ongoing[0].peak, 100.0);
+        assert!(!ongoing[0].relative);
+        assert!((ongoing[0].baseline - 11.0).abs() < 1.0);
+
+        /* It ends after as many normal readings as it took to start */
+        let next = observe(&mut detector, next, &steady(2));
+        assert_eq!(events(&detector)[0].end, None);
+        observe(&mut detector, next, &steady(1));
+        let ended = events(&detector);
+        assert_eq!(ended.len(), 1);
+        assert!(ended[0].end.is_some());
+    }
+
+    #[test]
+    fn spikes_during_warm_up_or_shorter_than_the_minimum_are_not_events() {
+        let mut latencies = steady(100);
+        latencies[50..60].fill(100);
+        let mut detector = AnomalyDetector::new(AnomalyConfig::default());
+        let next = observe(&mut detector, 0, &latencies);
+        assert!(events(&detector).is_empty());
+
+        /* Two readings are under the three needed */
+        let next = observe(&mut detector, next, &[100, 100]);
+        observe(&mut detector, next, &steady(10));
+        assert!(events(&detector).is_empty());
+    }
+
+    #[test]
+    fn small_wobbles_are_not_events() {
+        let mut detector = AnomalyDetector::new(AnomalyConfig::default());
+        let mut latencies = steady(200);
+        for latency in latencies.iter_mut().skip(100).step_by(3) {
+            *latency = 14;
+        }
+        observe(&mut detector, 0, &latencies);
+
+        assert!(events(&detector).is_empty());
+    }
+
+    #[test]
+    fn relative_threshold_compares_with_the_usual_latency_for_the_hour() {
+        let config = AnomalyConfig {
+            relative_threshold: Some(3.0),
+            ..AnomalyConfig::default()
+        };
+        let mut detector = AnomalyDetector::new(config);
+        observe(&mut detector, 0, &[10; 100]);
+
+        /* The hour is learned once the next one starts */
+        detector.observe(&reading(60 * 60, 100, 10));
+
+        let week = 7 * 24 * 60 * 60;
+        for (i, latency_ms) in [25, 25, 25, 10, 10, 10].iter().enumerate() {
+            detector.observe(&reading(week + i as u64, 101 + i as u64, *latency_ms));
+        }
+        assert!(events(&detector).is_empty());
+
+        for (i, latency_ms) in [50, 40, 35].iter().enumerate() {
+            detector.observe(&reading(week + 10 + i as u64, 107 + i as u64, *latency_ms));
+        }
+        let events = events(&detector);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].relative);
+        assert_eq!(events[0].baseline, 10.0);
+        assert_eq!(events[0].peak, 50.0);
+        assert_eq!(events[0].peak_score, 5.0);
+    }
+
+    #[test]
+    fn relative_threshold_must_be_over_one() {
+        let with_threshold = |relative_threshold| AnomalyConfig {
+            relative_threshold: Some(relative_threshold),
+            ..AnomalyConfig::default()
+        };
+
+        assert!(with_threshold(1.5).validate().is_ok());
+        assert!(with_threshold(1.0).validate().is_err());
+        assert!(with_threshold(0.5).validate().is_err());
+        assert!(with_threshold(f64::NAN).validate().is_err());
+        assert!(with_threshold(f64::INFINITY).validate().is_err());
+    }
+
+    #[test]
+    fn lost_packets_after_warm_up_are_a_loss_event() {
+        let mut detector = AnomalyDetector::new(AnomalyConfig::default());
+        let next = observe(&mut detector, 0, &steady(100));
+
+        /* Every other packet is lost */
+        for i in 0..10 {
+            let index = next + i;
+            detector.observe(&reading(index, next + 2 * i + 1, 10));
+        }
+
+        let events = events(&detector);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AnomalyKind::HighLoss);
+        assert!(events[0].peak > 30.0);
+    }
+}
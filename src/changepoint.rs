@@ -0,0 +1,314 @@
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::anomaly::{AnomalyConfig, MAX_ANOMALY_EVENTS};
+use crate::ping::PingReading;
+use crate::stats::{percentile, TimeRange};
+
+/* A sum that never crosses the threshold would otherwise keep every reading */
+const MAX_CANDIDATE_READINGS: usize = 10_000;
+
+/* A lasting change in latency, from the reading where it is estimated to have
+ * started until the one that confirmed it. Levels are medians in ms.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LevelShift {
+    pub start: SystemTime,
+    pub detected_at: SystemTime,
+    pub before_ms: f64,
+    pub after_ms: f64,
+    pub readings: usize,
+}
+
+impl LevelShift {
+    fn overlaps(&self, range: &TimeRange) -> bool {
+        range.end.is_none_or(|end| self.start <= end)
+            && range.start.is_none_or(|start| self.detected_at >= start)
+    }
+}
+
+/* A shift and the readings it was detected from, which are only included
+ * when a query asks for them.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LevelShiftReport {
+    pub shift: LevelShift,
+    pub readings: Vec<PingReading>,
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    percentile(&sorted, 50.0)
+}
+
+/* One side of a CUSUM, it accumulates how far readings are past the reference
+ * level and restarts whenever that falls back to zero, so the change is
+ * estimated to start at the first reading after the last restart.
+ */
+#[derive(Debug, Default)]
+struct CumulativeSum {
+    sum: f64,
+    start: Option<SystemTime>,
+    readings: VecDeque<f64>,
+}
+
+impl CumulativeSum {
+    fn observe(&mut self, time: SystemTime, latency: f64, increment: f64) {
+        self.sum = (self.sum + increment).max(0.0);
+
+        if self.sum == 0.0 {
+            self.reset();
+            return;
+        }
+
+        self.start.get_or_insert(time);
+        self.readings.push_back(latency);
+        if self.readings.len() > MAX_CANDIDATE_READINGS {
+            self.readings.pop_front();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.sum = 0.0;
+        self.start = None;
+        self.readings.clear();
+    }
+}
+
+/* A shift the sums crossed the threshold for, it is only reported if the
+ * readings that follow stay at the new level, so a long spike is not taken
+ * for a shift.
+ */
+#[derive(Debug)]
+struct Candidate {
+    start: SystemTime,
+    direction: f64,
+    readings: Vec<f64>,
+    confirmation: Vec<f64>,
+}
+
+/* Runs a two-sided CUSUM over latency against the level before the last
+ * shift. Readings are clipped at the anomaly sensitivity, so it takes a
+ * change that lasts to cross the threshold rather than a few large spikes.
+ */
+#[derive(Debug, Default)]
+pub struct LevelShiftDetector {
+    reference: Option<f64>,
+    upper: CumulativeSum,
+    lower: CumulativeSum,
+    candidate: Option<Candidate>,
+    shifts: VecDeque<LevelShift>,
+}
+
+impl LevelShiftDetector {
+    /* `baseline` is the learned mean and spread of latency, or none until
+     * there are enough readings for it. Returns the new level once a shift is
+     * confirmed.
+     */
+    pub fn observe(
+        &mut self,
+        time: SystemTime,
+        latency: f64,
+        baseline: Option<(f64, f64)>,
+        config: &AnomalyConfig,
+    ) -> Option<f64> {
+        let (mean, spread) = baseline?;
+        let reference = *self.reference.get_or_insert(mean);
+
+        if let Some(candidate) = &mut self.candidate {
+            candidate.confirmation.push(latency);
+            if candidate.confirmation.len() < candidate.readings.len() {
+                return None;
+            }
+
+            let candidate = self.candidate.take()?;
+            return self.confirm(candidate, time, reference, config);
+        }
+
+        let limit = config.sensitivity * spread;
+        let deviation = (latency - reference).clamp(-limit, limit);
+        let allowance = (spread / 2.0).max(config.min_level_shift_ms / 2.0);
+
+        self.upper.observe(time, latency, deviation - allowance);
+        self.lower.observe(time, latency, -deviation - allowance);
+
+        let threshold = config.level_shift_threshold * spread;
+        let (side, direction) = if self.upper.sum > threshold {
+            (&mut self.upper, 1.0)
+        } else if self.lower.sum > threshold {
+            (&mut self.lower, -1.0)
+        } else {
+            return None;
+        };
+
+        self.candidate = side.start.map(|start| Candidate {
+            start,
+            direction,
+            readings: side.readings.drain(..).collect(),
+            confirmation: vec![],
+        });
+        self.upper.reset();
+        self.lower.reset();
+
+        None
+    }
+
+    fn confirm(
+        &mut self,
+        candidate: Candidate,
+        time: SystemTime,
+        reference: f64,
+        config: &AnomalyConfig,
+    ) -> Option<f64> {
+        let Candidate {
+            start,
+            direction,
+            mut readings,
+            confirmation,
+        } = candidate;
+
+        /* The readings after the threshold was crossed must be at least half
+         * way to the new level
+         */
+        let confirmed_change = (median(&confirmation) - reference) * direction;
+        readings.extend(confirmation);
+        let after = median(&readings);
+        let change = (after - reference) * direction;
+
+        if change < config.min_level_shift_ms.max(f64::EPSILON) || confirmed_change < change / 2.0 {
+            return None;
+        }
+
+        self.shifts.push_back(LevelShift {
+            start,
+            detected_at: time,
+            before_ms: reference,
+            after_ms: after,
+            readings: readings.len(),
+        });
+        while self.shifts.len() > MAX_ANOMALY_EVENTS {
+            self.shifts.pop_front();
+        }
+
+        self.reference = Some(after);
+        Some(after)
+    }
+
+    pub fn shifts(&self, range: &TimeRange) -> Vec<LevelShift> {
+        self.shifts
+            .iter()
+            .filter(|shift| shift.overlaps(range))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /* A learned mean of 11 ms with a spread of 1 ms */
+    const BASELINE: Option<(f64, f64)> = Some((11.0, 1.0));
+
+    fn time(seconds: usize) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + seconds as u64)
+    }
+
+    /* Returns the levels of confirmed shifts */
+    fn observe(
+        detector: &mut LevelShiftDetector,
+        latencies_ms: &[f64],
+        baseline: Option<(f64, f64)>,
+    ) -> Vec<f64> {
+        latencies_ms
+            .iter()
+            .enumerate()
+            .filter_map(|(i, latency)| {
+                detector.observe(time(i), *latency, baseline, &AnomalyConfig::default())
+            })
+            .collect()
+    }
+
+    fn shifts(detector: &LevelShiftDetector) -> Vec<LevelShift> {
+        detector.shifts(&TimeRange::default())
+    }
+
+    #[test]
+    fn lasting_change_is_a_level_shift() {
+        let mut latencies = vec![11.0; 50];
+        latencies.extend([31.0; 100]);
+        let mut detector = LevelShiftDetector::default();
+
+        assert_eq!(observe(&mut detector, &latencies, BASELINE), [31.0]);
+
+        let shifts = shifts(&detector);
+        assert_eq!(shifts.len(), 1);
+        assert_eq!(shifts[0].start, time(50));
+        assert_eq!(shifts[0].before_ms, 11.0);
+        assert_eq!(shifts[0].after_ms, 31.0);
+        assert!(shifts[0].detected_at > shifts[0].start);
+    }
+
+    #[test]
+    fn drop_in_latency_is_a_level_shift() {
+        let mut latencies = vec![11.0; 50];
+        latencies.extend([1.0; 100]);
+        let mut detector = LevelShiftDetector::default();
+
+        assert_eq!(observe(&mut detector, &latencies, BASELINE), [1.0]);
+    }
+
+    #[test]
+    fn short_or_large_spikes_are_not_level_shifts() {
+        /* Each reading adds at most the sensitivity, so a large spike takes
+         * as long to cross the threshold as a moderate one.
+         */
+        let mut latencies = vec![11.0; 50];
+        latencies.extend([500.0; 10]);
+        latencies.extend([11.0; 100]);
+        let mut detector = LevelShiftDetector::default();
+
+        assert!(observe(&mut detector, &latencies, BASELINE).is_empty());
+        assert!(shifts(&detector).is_empty());
+    }
+
+    #[test]
+    fn spike_that_crosses_the_threshold_but_ends_is_not_a_level_shift() {
+        let mut latencies = vec![11.0; 50];
+        latencies.extend([31.0; 35]);
+        latencies.extend([11.0; 100]);
+        let mut detector = LevelShiftDetector::default();
+
+        assert!(observe(&mut detector, &latencies, BASELINE).is_empty());
+        assert!(shifts(&detector).is_empty());
+    }
+
+    #[test]
+    fn change_under_the_minimum_is_not_a_level_shift() {
+        let mut latencies = vec![11.0; 50];
+        latencies.extend([14.0; 1000]);
+        let mut detector = LevelShiftDetector::default();
+
+        assert!(observe(&mut detector, &latencies, BASELINE).is_empty());
+    }
+
+    #[test]
+    fn nothing_is_detected_before_the_baseline_is_learned() {
+        let mut latencies = vec![11.0; 50];
+        latencies.extend([31.0; 100]);
+        let mut detector = LevelShiftDetector::default();
+
+        assert!(observe(&mut detector, &latencies, None).is_empty());
+        assert!(shifts(&detector).is_empty());
+
+        /* The reference is the first learned baseline, not the readings
+         * seen before it.
+         */
+        assert!(observe(&mut detector, &[31.0; 100], Some((31.0, 1.0))).is_empty());
+    }
+}
@@ -2,35 +2,286 @@ use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::time::{Duration, SystemTime};
 
+use crate::alerts::AlertReport;
+use crate::anomaly::{AnomalyEvent, AnomalyKind};
+use crate::changepoint::LevelShiftReport;
 use crate::config::PingMonitorConfig;
-use crate::ping::PingReading;
-use crate::server::{ServerResponse, TargetAndPingReadingQuery};
+use crate::diagnostics::ServiceDiagnostics;
+use crate::health::TargetStatus;
+use crate::histogram::{LatencyHeatmap, LatencyHistogram};
+use crate::ping::{PingEpisode, PingReading};
+use crate::protocol::{
+    Envelope, Hello, HelloResponse, ProtocolError, CAPABILITY_ALERTS, CAPABILITY_ANOMALIES,
+    CAPABILITY_COMPARISON, CAPABILITY_DIAGNOSTICS, CAPABILITY_EPISODES, CAPABILITY_HEATMAP,
+    CAPABILITY_HISTOGRAM, CAPABILITY_LEVEL_SHIFTS, CAPABILITY_MANAGE_TARGETS, CAPABILITY_SEASONAL,
+    CAPABILITY_SLA, CAPABILITY_STATISTICS, CAPABILITY_STATUS, CAPABILITY_SUBSCRIBE,
+};
+use crate::remote::{connect_tls, RemoteOptions};
+use crate::server::{
+    ServerResponse, TargetAndAlertQuery, TargetAndAnomalyQuery, TargetAndComparisonQuery,
+    TargetAndHeatmapQuery, TargetAndHistogramQuery, TargetAndLevelShiftQuery,
+    TargetAndMonitorConfig, TargetAndPingReadingQuery, TargetAndRelativePingReadingQuery,
+    TargetAndSeasonalBaselineQuery, TargetAndSlaQuery, TargetAndStatisticsQuery,
+    TargetAndStatusQuery, TargetSubscription,
+};
+use crate::sla::{SlaPeriod, SlaReport};
+use crate::stats::{HistoryCoverage, PeriodComparison, PeriodStatistics, PingStatistics};
+use crate::subscription::SubscriptionEvent;
 use crate::util::{receive_length_prefixed_object, send_length_prefixed_object};
+use futures_rustls::rustls::{ClientConnection, StreamOwned};
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
 pub enum ClientCommand {
     TargetAndPingReadingQuery(TargetAndPingReadingQuery),
+    TargetAndStatisticsQuery(TargetAndStatisticsQuery),
+    TargetAndComparisonQuery(TargetAndComparisonQuery),
+    TargetAndSlaQuery(TargetAndSlaQuery),
+    TargetAndHistogramQuery(TargetAndHistogramQuery),
+    TargetAndHeatmapQuery(TargetAndHeatmapQuery),
+    Subscribe(TargetSubscription),
+    Authenticate(String),
     Disconnect,
+    AddTarget(TargetAndMonitorConfig),
+    RemoveTarget(String),
+    PauseTarget(String),
+    ResumeTarget(String),
+    TargetAndStatusQuery(TargetAndStatusQuery),
+    Diagnostics,
+    TargetAndAnomalyQuery(TargetAndAnomalyQuery),
+    TargetAndLevelShiftQuery(TargetAndLevelShiftQuery),
+    TargetAndRelativePingReadingQuery(TargetAndRelativePingReadingQuery),
+    TargetAndSeasonalBaselineQuery(TargetAndSeasonalBaselineQuery),
+    TargetAndAlertQuery(TargetAndAlertQuery),
 }
 
-pub fn send_client_command(command: ClientCommand) -> anyhow::Result<ServerResponse> {
-    let mut stream = UnixStream::connect(crate::UNIX_SOCKET_PATH)?;
+impl ClientCommand {
+    pub fn required_capability(&self) -> Option<&'static str> {
+        match self {
+            ClientCommand::TargetAndPingReadingQuery(_) => Some(CAPABILITY_EPISODES),
+            ClientCommand::TargetAndStatisticsQuery(_) => Some(CAPABILITY_STATISTICS),
+            ClientCommand::TargetAndComparisonQuery(_) => Some(CAPABILITY_COMPARISON),
+            ClientCommand::TargetAndSlaQuery(_) => Some(CAPABILITY_SLA),
+            ClientCommand::TargetAndHistogramQuery(_) => Some(CAPABILITY_HISTOGRAM),
+            ClientCommand::TargetAndHeatmapQuery(_) => Some(CAPABILITY_HEATMAP),
+            ClientCommand::Subscribe(_) => Some(CAPABILITY_SUBSCRIBE),
+            ClientCommand::AddTarget(_)
+            | ClientCommand::RemoveTarget(_)
+            | ClientCommand::PauseTarget(_)
+            | ClientCommand::ResumeTarget(_) => Some(CAPABILITY_MANAGE_TARGETS),
+            ClientCommand::TargetAndStatusQuery(_) => Some(CAPABILITY_STATUS),
+            ClientCommand::Diagnostics => Some(CAPABILITY_DIAGNOSTICS),
+            ClientCommand::TargetAndAnomalyQuery(_) => Some(CAPABILITY_ANOMALIES),
+            ClientCommand::TargetAndLevelShiftQuery(_) => Some(CAPABILITY_LEVEL_SHIFTS),
+            ClientCommand::TargetAndRelativePingReadingQuery(_)
+            | ClientCommand::TargetAndSeasonalBaselineQuery(_) => Some(CAPABILITY_SEASONAL),
+            ClientCommand::TargetAndAlertQuery(_) => Some(CAPABILITY_ALERTS),
+            ClientCommand::Authenticate(_) | ClientCommand::Disconnect => None,
+        }
+    }
+
+    /* Commands that change the running service rather than query it */
+    pub fn is_management(&self) -> bool {
+        matches!(
+            self,
+            ClientCommand::AddTarget(_)
+                | ClientCommand::RemoveTarget(_)
+                | ClientCommand::PauseTarget(_)
+                | ClientCommand::ResumeTarget(_)
+        )
+    }
+}
+
+/* Where the service is reached, local sockets are trusted while remote
+ * services are reached over TLS and may need a token.
+ */
+#[derive(Debug, Clone)]
+pub enum ServiceAddress {
+    Local(PathBuf),
+    Remote(RemoteOptions),
+}
+
+/* Responses can hold every reading in a target's history, so they are
+ * allowed to be much larger than the commands the service accepts.
+ */
+pub const MAX_RESPONSE_FRAME_SIZE: u64 = 256 * 1024 * 1024;
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+trait ServiceStream: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl ServiceStream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl ServiceStream for StreamOwned<ClientConnection, TcpStream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+pub struct ServerConnection {
+    stream: Box<dyn ServiceStream>,
+    protocol_version: u32,
+    server: Hello,
+}
+
+impl ServerConnection {
+    pub fn connect(address: &ServiceAddress) -> anyhow::Result<ServerConnection> {
+        let stream: Box<dyn ServiceStream> = match address {
+            ServiceAddress::Local(socket_path) => {
+                Box::new(UnixStream::connect(socket_path).map_err(|e| {
+                    anyhow::anyhow!(
+                        "Could not connect to the monitor service at {}: {e}",
+                        socket_path.display()
+                    )
+                })?)
+            }
+            ServiceAddress::Remote(options) => Box::new(connect_tls(options)?),
+        };
+
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+
+        let mut connection = ServerConnection::handshake(stream)?;
+
+        if let ServiceAddress::Remote(RemoteOptions {
+            token: Some(token), ..
+        }) = address
+        {
+            connection.send_command(&ClientCommand::Authenticate(token.clone()))?;
+
+            match connection.receive_response()? {
+                ServerResponse::AuthenticationResult(Ok(())) => {}
+                _ => anyhow::bail!("Unexpected server response"),
+            }
+        }
+
+        Ok(connection)
+    }
+
+    fn handshake(mut stream: Box<dyn ServiceStream>) -> anyhow::Result<ServerConnection> {
+        let client_hello = Hello::new(&[]);
+        send_length_prefixed_object(&client_hello, &mut stream)?;
 
-    send_length_prefixed_object(&command, &mut stream)?;
-    let response = receive_length_prefixed_object(&mut stream)?;
+        let HelloResponse { server, result } =
+            receive_length_prefixed_object(&mut stream, MAX_RESPONSE_FRAME_SIZE)?;
 
-    send_length_prefixed_object(&ClientCommand::Disconnect, &mut stream)?;
+        let protocol_version = result.map_err(ProtocolError::Rejected)?;
+        if client_hello.negotiate(&server)? != protocol_version {
+            anyhow::bail!("Service negotiated an unexpected protocol version {protocol_version}");
+        }
+
+        Ok(ServerConnection {
+            stream,
+            protocol_version,
+            server,
+        })
+    }
+
+    pub fn send_command(&mut self, command: &ClientCommand) -> anyhow::Result<()> {
+        if let Some(capability) = command.required_capability() {
+            if !self.server.has_capability(capability) {
+                anyhow::bail!(ProtocolError::MissingCapability(capability));
+            }
+        }
+
+        Ok(send_length_prefixed_object(
+            &Envelope::seal(self.protocol_version, command)?,
+            &mut self.stream,
+        )?)
+    }
+
+    /* `None` waits for responses forever */
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> anyhow::Result<()> {
+        Ok(self.stream.set_read_timeout(timeout)?)
+    }
+
+    pub fn receive_response(&mut self) -> anyhow::Result<ServerResponse> {
+        let envelope: Envelope =
+            receive_length_prefixed_object(&mut self.stream, MAX_RESPONSE_FRAME_SIZE)?;
+
+        match envelope.open(self.protocol_version)? {
+            ServerResponse::AuthenticationResult(Err(e)) => {
+                anyhow::bail!("Service rejected authentication: {e}")
+            }
+            ServerResponse::Error(e) => anyhow::bail!("Service returned an error: {e}"),
+            response => Ok(response),
+        }
+    }
+}
+
+pub fn send_client_command(
+    address: &ServiceAddress,
+    command: ClientCommand,
+) -> anyhow::Result<ServerResponse> {
+    let mut connection = ServerConnection::connect(address)?;
+
+    connection.send_command(&command)?;
+    let response = connection.receive_response()?;
+
+    connection.send_command(&ClientCommand::Disconnect)?;
 
     Ok(response)
 }
 
+/* Prints events as they arrive until the service ends the subscription,
+ * a closed connection is reported as an error since watching never ends
+ * on its own.
+ */
+pub fn watch_subscription(
+    address: &ServiceAddress,
+    subscription: TargetSubscription,
+    options: &PingQueryResultDisplayOptions,
+) -> anyhow::Result<()> {
+    let mut connection = ServerConnection::connect(address)?;
+
+    connection.send_command(&ClientCommand::Subscribe(subscription))?;
+
+    match connection.receive_response()? {
+        ServerResponse::Subscribed(targets) if targets.is_empty() => {
+            anyhow::bail!("No matching targets to watch");
+        }
+        ServerResponse::Subscribed(targets) => {
+            println!(">>>>>>>>>> Watching {} <<<<<<<<<<", targets.join(", "));
+        }
+        _ => anyhow::bail!("Unexpected server response"),
+    }
+
+    /* Events only arrive when there are readings, which may be never */
+    connection.set_read_timeout(None)?;
+
+    loop {
+        let response = connection
+            .receive_response()
+            .map_err(|e| anyhow::anyhow!("Lost connection to the monitor service: {e}"))?;
+
+        match response {
+            ServerResponse::SubscriptionEvent(SubscriptionEvent::Reading { target, reading }) => {
+                print!("[{}] {target}: ", format_time(reading.timestamp, options));
+                display_ping_reading(&reading, options);
+            }
+            ServerResponse::SubscriptionEvent(SubscriptionEvent::Lagged { target, missed }) => {
+                println!("!!!!! Missed {missed} reading(s) from {target}, output is falling behind !!!!!");
+            }
+            _ => anyhow::bail!("Unexpected server response"),
+        }
+    }
+}
+
 pub struct PingQueryResultDisplayOptions {
     pub display_skip_warning_threshold: Option<Duration>,
     pub time_format: Option<String>,
     pub show_original_line: bool,
+    pub expand_episode: Option<usize>,
+    pub expand_all_episodes: bool,
 }
 
 fn display_ping_reading(reading: &PingReading, options: &PingQueryResultDisplayOptions) {
@@ -41,22 +292,23 @@ fn display_ping_reading(reading: &PingReading, options: &PingQueryResultDisplayO
     }
 }
 
-fn display_current_time(time: SystemTime, options: &PingQueryResultDisplayOptions) {
+fn format_time(time: SystemTime, options: &PingQueryResultDisplayOptions) -> String {
     let chrono_time = DateTime::<Local>::from(time);
 
     let format_string = options.time_format.as_ref().map_or("%F %X", |s| s.as_str());
 
-    println!("===== At {} =====", chrono_time.format(format_string));
+    chrono_time.format(format_string).to_string()
 }
 
-fn display_readings_for_target(
-    target: &str,
+fn display_current_time(time: SystemTime, options: &PingQueryResultDisplayOptions) {
+    println!("===== At {} =====", format_time(time, options));
+}
+
+fn display_readings(
     readings: &[PingReading],
     monitor_config: &PingMonitorConfig,
     options: &PingQueryResultDisplayOptions,
 ) {
-    println!("Target: {target}");
-
     let mut errors: Vec<String> = vec![];
     let display_skip_warning_threshold =
         options
@@ -87,13 +339,639 @@ fn display_readings_for_target(
     }
 }
 
+fn display_episode(index: usize, episode: &PingEpisode, options: &PingQueryResultDisplayOptions) {
+    let baseline = episode
+        .baseline_latency
+        .map_or(String::from("unknown"), |latency| {
+            format!("{} ms", latency.as_millis())
+        });
+
+    println!(
+        "[{index}] {} to {} ({}s), {} reading(s) over threshold, peak {} ms, median {} ms, baseline {baseline}",
+        format_time(episode.start, options),
+        format_time(episode.end, options),
+        episode.duration.as_secs(),
+        episode.over_threshold_count,
+        episode.peak_latency.as_millis(),
+        episode.median_latency.as_millis(),
+    );
+}
+
+fn display_episodes_for_target(
+    target: &str,
+    episodes: &[PingEpisode],
+    monitor_config: &PingMonitorConfig,
+    options: &PingQueryResultDisplayOptions,
+) {
+    println!("Target: {target}, {} episode(s)", episodes.len());
+
+    for (index, episode) in episodes.iter().enumerate() {
+        display_episode(index, episode, options);
+
+        if options.expand_all_episodes || options.expand_episode == Some(index) {
+            display_readings(&episode.readings, monitor_config, options);
+        }
+    }
+}
+
 pub fn display_ping_query_results(
-    results: &HashMap<String, (Vec<PingReading>, PingMonitorConfig)>,
+    results: &HashMap<String, (Vec<PingEpisode>, PingMonitorConfig)>,
     options: &PingQueryResultDisplayOptions,
 ) {
-    for (target, (readings, monitor_config)) in results {
-        display_readings_for_target(target, readings, monitor_config, options);
+    for (target, (episodes, monitor_config)) in results {
+        display_episodes_for_target(target, episodes, monitor_config, options);
     }
 
     println!(">>>>>>>>>> {} target(s) found <<<<<<<<<<", results.len());
 }
+
+pub fn display_statistics_results(
+    results: &HashMap<String, Option<PingStatistics>>,
+    json: bool,
+) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(results)?);
+        return Ok(());
+    }
+
+    let mut targets: Vec<&String> = results.keys().collect();
+    targets.sort();
+
+    println!(
+        "{:<24} {:>8} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9} {:>8}",
+        "TARGET",
+        "COUNT",
+        "MIN",
+        "MAX",
+        "MEAN",
+        "MEDIAN",
+        "P90",
+        "P95",
+        "P99",
+        "STDDEV",
+        "JITTER",
+        "LOSS"
+    );
+
+    for target in targets {
+        match &results[target] {
+            Some(statistics) => println!(
+                "{:<24} {:>8} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>7.2}%",
+                target,
+                statistics.count,
+                statistics.min_ms,
+                statistics.max_ms,
+                statistics.mean_ms,
+                statistics.median_ms,
+                statistics.p90_ms,
+                statistics.p95_ms,
+                statistics.p99_ms,
+                statistics.std_dev_ms,
+                statistics.jitter_ms,
+                statistics.loss_percent,
+            ),
+            None => println!("{:<24} no readings in range", target),
+        }
+    }
+
+    println!(
+        ">>>>>>>>>> {} target(s) found, latencies in ms <<<<<<<<<<",
+        results.len()
+    );
+
+    Ok(())
+}
+
+fn format_optional_time(time: Option<SystemTime>, default: &str) -> String {
+    time.map_or(String::from(default), |time| {
+        DateTime::<Local>::from(time).format("%F %X").to_string()
+    })
+}
+
+fn display_period(label: &str, period: &PeriodStatistics) {
+    let coverage = match period.coverage {
+        HistoryCoverage::Complete => String::from("complete"),
+        HistoryCoverage::Partial { history_start } => format!(
+            "PARTIAL, history only starts at {}",
+            format_optional_time(Some(history_start), "")
+        ),
+        HistoryCoverage::OutsideHistory => String::from("OUTSIDE RETAINED HISTORY"),
+    };
+
+    println!(
+        "  {label}: {} to {} ({coverage})",
+        format_optional_time(period.range.start, "start of history"),
+        format_optional_time(period.range.end, "now"),
+    );
+}
+
+const COMPARISON_METRIC_NAMES: [&str; 7] =
+    ["mean", "median", "p95", "p99", "stddev", "jitter", "loss %"];
+
+fn comparison_metrics(statistics: &PingStatistics) -> [f64; 7] {
+    [
+        statistics.mean_ms,
+        statistics.median_ms,
+        statistics.p95_ms,
+        statistics.p99_ms,
+        statistics.std_dev_ms,
+        statistics.jitter_ms,
+        statistics.loss_percent,
+    ]
+}
+
+fn display_comparison_for_target(target: &str, comparison: &PeriodComparison) {
+    println!("Target: {target}");
+    display_period("first", &comparison.first);
+    display_period("second", &comparison.second);
+
+    let first = comparison.first.statistics.as_ref().map(comparison_metrics);
+    let second = comparison
+        .second
+        .statistics
+        .as_ref()
+        .map(comparison_metrics);
+    let differences = comparison.difference.as_ref().map(|difference| {
+        [
+            difference.mean_ms,
+            difference.median_ms,
+            difference.p95_ms,
+            difference.p99_ms,
+            difference.std_dev_ms,
+            difference.jitter_ms,
+            difference.loss_percent,
+        ]
+    });
+
+    let format_metric = |metrics: Option<[f64; 7]>, i: usize, format: fn(f64) -> String| {
+        metrics.map_or(String::from("-"), |metrics| format(metrics[i]))
+    };
+
+    println!(
+        "  {:<8} {:>10} {:>10} {:>10}",
+        "METRIC", "FIRST", "SECOND", "DIFF"
+    );
+    for (i, name) in COMPARISON_METRIC_NAMES.iter().enumerate() {
+        println!(
+            "  {:<8} {:>10} {:>10} {:>10}",
+            name,
+            format_metric(first, i, |value| format!("{value:.1}")),
+            format_metric(second, i, |value| format!("{value:.1}")),
+            format_metric(differences, i, |value| format!("{value:+.1}")),
+        );
+    }
+
+    if comparison.difference.is_none() {
+        println!("  no difference given, a period is missing readings or is not fully retained");
+    }
+}
+
+pub fn display_comparison_results(
+    results: &HashMap<String, PeriodComparison>,
+    json: bool,
+) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(results)?);
+        return Ok(());
+    }
+
+    let mut targets: Vec<&String> = results.keys().collect();
+    targets.sort();
+
+    for target in targets {
+        display_comparison_for_target(target, &results[target]);
+    }
+
+    println!(
+        ">>>>>>>>>> {} target(s) found, latencies in ms <<<<<<<<<<",
+        results.len()
+    );
+
+    Ok(())
+}
+
+fn display_sla_report_for_target(target: &str, report: &SlaReport) {
+    println!(
+        "Target: {target}, {}s intervals under {} ms with no loss",
+        report.config.interval_seconds, report.config.latency_threshold_ms
+    );
+
+    println!(
+        "  {:<20} {:>10} {:>10} {:>13}",
+        "PERIOD", "INTERVALS", "GOOD", "AVAILABILITY"
+    );
+    for period_report in &report.periods {
+        let period_start = DateTime::<Local>::from(period_report.period_start);
+        let label = match report.period {
+            SlaPeriod::Day => period_start.format("%F").to_string(),
+            SlaPeriod::Week => period_start.format("week of %F").to_string(),
+            SlaPeriod::Month => period_start.format("%Y-%m").to_string(),
+        };
+
+        println!(
+            "  {:<20} {:>10} {:>10} {:>12.3}%",
+            label,
+            period_report.total_intervals,
+            period_report.good_intervals,
+            period_report.availability_percent
+        );
+    }
+
+    if report.periods.is_empty() {
+        println!("  no readings in range");
+    }
+}
+
+pub fn display_sla_results(results: &HashMap<String, SlaReport>, json: bool) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(results)?);
+        return Ok(());
+    }
+
+    let mut targets: Vec<&String> = results.keys().collect();
+    targets.sort();
+
+    for target in targets {
+        display_sla_report_for_target(target, &results[target]);
+    }
+
+    println!(">>>>>>>>>> {} target(s) found <<<<<<<<<<", results.len());
+
+    Ok(())
+}
+
+pub fn display_status_results(
+    results: &HashMap<String, TargetStatus>,
+    json: bool,
+) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(results)?);
+        return Ok(());
+    }
+
+    let mut targets: Vec<&String> = results.keys().collect();
+    targets.sort();
+
+    println!(
+        "{:<24} {:<14} {:<20} {:<20} {:>9} {:>8}",
+        "TARGET", "STATE", "SINCE", "LAST READING", "LATENCY", "LOSS"
+    );
+
+    for target in targets {
+        let status = &results[target];
+        println!(
+            "{:<24} {:<14} {:<20} {:<20} {:>9} {:>8}",
+            target,
+            status.state.to_string(),
+            format_optional_time(Some(status.state_since), "-"),
+            format_optional_time(status.last_reading_time, "-"),
+            status.last_latency.map_or_else(
+                || String::from("-"),
+                |latency| format!("{} ms", latency.as_millis())
+            ),
+            status
+                .recent_loss_percent
+                .map_or_else(|| String::from("-"), |loss| format!("{loss:.2}%")),
+        );
+    }
+
+    println!(">>>>>>>>>> {} target(s) found <<<<<<<<<<", results.len());
+
+    Ok(())
+}
+
+pub fn display_alert_report(report: &AlertReport, json: bool) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(report)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<20} {:<24} {:<10} {:<20} {:>12}",
+        "RULE", "TARGET", "STATE", "SINCE", "VALUE"
+    );
+
+    for alert in &report.active {
+        println!(
+            "{:<20} {:<24} {:<10} {:<20} {:>12}",
+            alert.rule,
+            alert.target,
+            alert.state.to_string(),
+            format_optional_time(Some(alert.state_since), "-"),
+            format!("{:.1} {}", alert.value, alert.metric.unit()),
+        );
+    }
+
+    println!(
+        ">>>>>>>>>> {} active alert(s) <<<<<<<<<<",
+        report.active.len()
+    );
+
+    for event in &report.history {
+        println!(
+            "{} {} {} for {}, {} {:.1} {unit}, threshold {:.1} {unit}",
+            format_optional_time(Some(event.time), "-"),
+            event.rule,
+            event.transition,
+            event.target,
+            event.metric,
+            event.value,
+            event.threshold,
+            unit = event.metric.unit(),
+        );
+    }
+
+    println!(
+        ">>>>>>>>>> {} alert transition(s) found <<<<<<<<<<",
+        report.history.len()
+    );
+
+    Ok(())
+}
+
+pub fn display_anomaly_results(
+    results: &HashMap<String, Vec<AnomalyEvent>>,
+    json: bool,
+) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(results)?);
+        return Ok(());
+    }
+
+    let mut targets: Vec<&String> = results.keys().collect();
+    targets.sort();
+
+    for target in targets {
+        let events = &results[target];
+        println!("Target: {target}, {} anomaly event(s)", events.len());
+
+        for event in events {
+            let unit = match event.kind {
+                AnomalyKind::HighLatency => "ms",
+                AnomalyKind::HighLoss => "% loss",
+            };
+
+            let (baseline, score) = if event.relative {
+                ("usual", format!("{:.1}x usual", event.peak_score))
+            } else {
+                ("baseline", format!("{:.1} deviations", event.peak_score))
+            };
+
+            println!(
+                "  {:<14} {} - {:<19} {:>5} reading(s), {baseline} {:.1} {unit}, peak {:.1} {unit} ({score})",
+                event.kind.to_string(),
+                format_optional_time(Some(event.start), "-"),
+                format_optional_time(event.end, "ongoing"),
+                event.readings,
+                event.baseline,
+                event.peak,
+            );
+        }
+    }
+
+    println!(">>>>>>>>>> {} target(s) found <<<<<<<<<<", results.len());
+
+    Ok(())
+}
+
+pub fn display_level_shift_results(
+    results: &HashMap<String, Vec<LevelShiftReport>>,
+    json: bool,
+) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(results)?);
+        return Ok(());
+    }
+
+    let mut targets: Vec<&String> = results.keys().collect();
+    targets.sort();
+
+    for target in targets {
+        let reports = &results[target];
+        println!("Target: {target}, {} level shift(s)", reports.len());
+
+        for LevelShiftReport { shift, readings } in reports {
+            println!(
+                "  {} - {:<19} {:>5} reading(s), {:.1} ms -> {:.1} ms ({:+.1} ms)",
+                format_optional_time(Some(shift.start), "-"),
+                format_optional_time(Some(shift.detected_at), "-"),
+                shift.readings,
+                shift.before_ms,
+                shift.after_ms,
+                shift.after_ms - shift.before_ms,
+            );
+
+            for reading in readings {
+                println!(
+                    "    {} {} ms",
+                    format_optional_time(Some(reading.timestamp), "-"),
+                    reading.latency.as_millis()
+                );
+            }
+        }
+    }
+
+    println!(">>>>>>>>>> {} target(s) found <<<<<<<<<<", results.len());
+
+    Ok(())
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let seconds = uptime.as_secs();
+
+    format!(
+        "{}d {}h {}m {}s",
+        seconds / 86400,
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+pub fn display_diagnostics(diagnostics: &ServiceDiagnostics, json: bool) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(diagnostics)?);
+        return Ok(());
+    }
+
+    println!("Version:           {}", diagnostics.version);
+    println!(
+        "Started:           {} (up {})",
+        format_optional_time(Some(diagnostics.started_at), "-"),
+        format_uptime(diagnostics.uptime)
+    );
+    println!("Config:            {}", diagnostics.config_path.display());
+    println!("Socket:            {}", diagnostics.socket_path.display());
+    println!(
+        "Remote listen:     {}",
+        diagnostics.remote_listen.as_deref().unwrap_or("-")
+    );
+    println!(
+        "HTTP listen:       {}",
+        diagnostics.http_listen.as_deref().unwrap_or("-")
+    );
+    println!("Monitor tasks:     {}", diagnostics.monitor_tasks);
+    println!("Connected clients: {}", diagnostics.connected_clients);
+    println!(
+        "Pending notifications: {}",
+        diagnostics.pending_notifications
+    );
+    println!();
+
+    let mut targets: Vec<&String> = diagnostics.monitors.keys().collect();
+    targets.sort();
+
+    println!(
+        "{:<24} {:<8} {:>8} {:>8} {:>9} {:>10}  LAST PROBE ERROR",
+        "TARGET", "PROBE", "RESTARTS", "PARSE", "READINGS", "MEMORY"
+    );
+
+    for target in targets {
+        let monitor = &diagnostics.monitors[target];
+        let probe = match (monitor.paused, monitor.probe_running) {
+            (true, _) => "paused",
+            (false, true) => "running",
+            (false, false) => "stopped",
+        };
+
+        println!(
+            "{:<24} {:<8} {:>8} {:>8} {:>9} {:>7} KiB  {}",
+            target,
+            probe,
+            monitor.probe_restarts,
+            monitor.parse_failures,
+            monitor.readings,
+            monitor.history_memory_bytes / 1024,
+            monitor
+                .last_probe_error
+                .as_ref()
+                .map_or(String::from("-"), |error| format!(
+                    "{} at {}",
+                    error.message,
+                    format_optional_time(Some(error.time), "-")
+                )),
+        );
+    }
+
+    Ok(())
+}
+
+const HISTOGRAM_BAR_WIDTH: usize = 50;
+
+fn display_histogram_for_target(target: &str, histogram: &LatencyHistogram) {
+    println!("Target: {target}, {} reading(s)", histogram.total);
+
+    let largest_count = histogram
+        .buckets
+        .iter()
+        .map(|bucket| bucket.count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    for bucket in &histogram.buckets {
+        let label = match bucket.upper {
+            Some(upper) => format!("{}-{} ms", bucket.lower.as_millis(), upper.as_millis()),
+            None => format!(">= {} ms", bucket.lower.as_millis()),
+        };
+        let bar_length = bucket.count * HISTOGRAM_BAR_WIDTH / largest_count;
+
+        println!(
+            "  {:<16} {:>8} {}",
+            label,
+            bucket.count,
+            "#".repeat(bar_length)
+        );
+    }
+}
+
+pub fn display_histogram_results(
+    results: &HashMap<String, LatencyHistogram>,
+    json: bool,
+) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(results)?);
+        return Ok(());
+    }
+
+    let mut targets: Vec<&String> = results.keys().collect();
+    targets.sort();
+
+    for target in targets {
+        display_histogram_for_target(target, &results[target]);
+    }
+
+    println!(">>>>>>>>>> {} target(s) found <<<<<<<<<<", results.len());
+
+    Ok(())
+}
+
+const HEATMAP_SHADES: [char; 9] = ['.', ':', '-', '=', '+', '*', '#', '%', '@'];
+const HEATMAP_DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+fn display_heatmap_for_target(target: &str, heatmap: &LatencyHeatmap) {
+    let means = || {
+        heatmap
+            .cells
+            .iter()
+            .flatten()
+            .filter_map(|cell| cell.mean_ms)
+    };
+    let lowest = means().fold(f64::INFINITY, f64::min);
+    let highest = means().fold(f64::NEG_INFINITY, f64::max);
+
+    println!("Target: {target}, mean latency by hour of day");
+
+    print!("     ");
+    for hour in 0..24 {
+        print!("{hour:>3}");
+    }
+    println!();
+
+    for (day, hours) in HEATMAP_DAYS.iter().zip(&heatmap.cells) {
+        print!("  {day}");
+        for cell in hours {
+            let shade = cell.mean_ms.map_or(' ', |mean| {
+                let scale = if highest > lowest {
+                    (mean - lowest) / (highest - lowest)
+                } else {
+                    0.0
+                };
+                HEATMAP_SHADES[(scale * (HEATMAP_SHADES.len() - 1) as f64).round() as usize]
+            });
+            print!("{shade:>3}");
+        }
+        println!();
+    }
+
+    if lowest <= highest {
+        println!(
+            "  '{}' is {lowest:.1} ms, '{}' is {highest:.1} ms, blank is no readings",
+            HEATMAP_SHADES[0],
+            HEATMAP_SHADES[HEATMAP_SHADES.len() - 1]
+        );
+    } else {
+        println!("  no readings in range");
+    }
+}
+
+pub fn display_heatmap_results(
+    results: &HashMap<String, LatencyHeatmap>,
+    json: bool,
+) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(results)?);
+        return Ok(());
+    }
+
+    let mut targets: Vec<&String> = results.keys().collect();
+    targets.sort();
+
+    for target in targets {
+        display_heatmap_for_target(target, &results[target]);
+    }
+
+    println!(">>>>>>>>>> {} target(s) found <<<<<<<<<<", results.len());
+
+    Ok(())
+}
@@ -18,8 +18,11 @@ pub async fn watch<
     mut command: smol::process::Command,
     mut stdout_consumer: T,
     mut stderr_consumer: U,
-) {
-    let mut handle = command.spawn().unwrap();
+) -> anyhow::Result<()> {
+    let mut handle = command.spawn().map_err(|err| {
+        error!("Could not start command {command_identifier}: {err}");
+        anyhow::anyhow!("could not start command: {err}")
+    })?;
 
     let stdout = handle.stdout.take().unwrap();
     let stderr = handle.stderr.take().unwrap();
@@ -57,7 +60,7 @@ pub async fn watch<
         .await
         {
             error!("Watch command {command_identifier} stopped due to {err}");
-            break;
+            return Err(err);
         }
     }
 }
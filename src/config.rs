@@ -1,15 +1,61 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 
-use crate::ping::PingMonitor;
+use crate::access::AccessConfig;
+use crate::alerts::AlertRule;
+use crate::anomaly::AnomalyConfig;
+use crate::notify::{NotificationsConfig, WebhookConfig};
+use crate::sla::SlaConfig;
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+/* Ping itself takes longer intervals, but a target pinged less than daily is
+ * not being monitored.
+ */
+pub const MAX_INTERVAL_SECONDS: f32 = 24.0 * 60.0 * 60.0;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub struct PingMonitorConfig {
     #[serde(rename = "interval-seconds")]
     pub interval_seconds: f32,
     #[serde(rename = "history-length-hours")]
     pub history_length_hours: f32,
+    #[serde(default)]
+    pub sla: Option<SlaConfig>,
+    #[serde(default)]
+    pub anomaly: AnomalyConfig,
+}
+
+impl PingMonitorConfig {
+    /* Also checks the interval, since the history is sized from both */
+    pub fn history_length(&self) -> Result<Duration, String> {
+        if self.interval_seconds.is_nan()
+            || self.interval_seconds <= 0.0
+            || self.interval_seconds > MAX_INTERVAL_SECONDS
+        {
+            return Err(format!(
+                "interval must be a positive number of seconds up to {MAX_INTERVAL_SECONDS}, not {}",
+                self.interval_seconds
+            ));
+        }
+
+        if self.history_length_hours.is_nan() || self.history_length_hours <= 0.0 {
+            return Err(format!(
+                "history length must be a positive number of hours, not {}",
+                self.history_length_hours
+            ));
+        }
+
+        Duration::try_from_secs_f32(self.history_length_hours * 60_f32 * 60_f32).map_err(|_| {
+            format!(
+                "history length of {} hours is too long",
+                self.history_length_hours
+            )
+        })
+    }
 }
 
 type Target = String;
@@ -20,29 +66,194 @@ pub struct Config {
     ping_monitors: HashMap<Target, PingMonitorConfig>,
     #[serde(rename = "remove-existing-socket")]
     pub remove_existing_socket: Option<bool>,
+    #[serde(rename = "socket-path")]
+    pub socket_path: Option<PathBuf>,
+    #[serde(rename = "socket-mode")]
+    pub socket_mode: Option<u32>,
+    #[serde(rename = "socket-owner")]
+    pub socket_owner: Option<u32>,
+    #[serde(rename = "socket-group")]
+    pub socket_group: Option<u32>,
+    pub instance: Option<String>,
+    #[serde(rename = "seasonal-path")]
+    pub seasonal_path: Option<PathBuf>,
+    #[serde(rename = "query-server", default)]
+    pub query_server: QueryServerConfig,
+    pub remote: Option<RemoteConfig>,
+    pub http: Option<HttpConfig>,
+    pub access: Option<AccessConfig>,
+    #[serde(default)]
+    pub alerts: HashMap<String, AlertRule>,
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+    #[serde(default)]
+    pub webhooks: HashMap<String, WebhookConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoteConfig {
+    pub listen: String,
+    pub certificate: PathBuf,
+    #[serde(rename = "private-key")]
+    pub private_key: PathBuf,
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    #[serde(rename = "client-ca")]
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpConfig {
+    pub listen: String,
+    #[serde(default)]
+    pub metrics: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(default)]
+pub struct QueryServerConfig {
+    #[serde(rename = "max-connections")]
+    pub max_connections: usize,
+    #[serde(rename = "idle-timeout-seconds")]
+    pub idle_timeout_seconds: f32,
+    #[serde(rename = "read-timeout-seconds")]
+    pub read_timeout_seconds: f32,
+    #[serde(rename = "max-frame-size")]
+    pub max_frame_size: u64,
+}
+
+impl Default for QueryServerConfig {
+    fn default() -> Self {
+        QueryServerConfig {
+            max_connections: 64,
+            idle_timeout_seconds: 300.0,
+            read_timeout_seconds: 10.0,
+            max_frame_size: 1024 * 1024,
+        }
+    }
+}
+
+impl QueryServerConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_connections == 0 {
+            return Err(String::from("max-connections must be at least 1"));
+        }
+
+        for (name, seconds) in [
+            ("idle-timeout-seconds", self.idle_timeout_seconds),
+            ("read-timeout-seconds", self.read_timeout_seconds),
+        ] {
+            if seconds.is_nan() || seconds <= 0.0 || Duration::try_from_secs_f32(seconds).is_err() {
+                return Err(format!(
+                    "{name} must be a positive number of seconds, not {seconds}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::try_from_secs_f32(self.idle_timeout_seconds).unwrap_or(Duration::MAX)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::try_from_secs_f32(self.read_timeout_seconds).unwrap_or(Duration::MAX)
+    }
+}
+
+/* Sockets live in the user's runtime directory when there is one, falling
+ * back to the system one, named instances get their own socket so that
+ * several daemons can run side by side.
+ */
+pub fn default_socket_path(instance: Option<&str>) -> PathBuf {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/run"));
+
+    match instance {
+        Some(instance) => runtime_dir.join(format!("oxidenet-{instance}.sock")),
+        None => runtime_dir.join("oxidenet.sock"),
+    }
+}
+
+/* State kept across restarts lives in the user's state directory, which
+ * defaults to ~/.local/state, falling back to the system one when there is no
+ * home directory. Named instances get their own files like they get their own
+ * socket.
+ */
+pub fn default_state_path(instance: Option<&str>, name: &str) -> PathBuf {
+    let state_dir = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map(|state_home| state_home.join("oxidenet"))
+        .unwrap_or_else(|| PathBuf::from("/var/lib/oxidenet"));
+
+    match instance {
+        Some(instance) => state_dir.join(format!("{name}-{instance}.json")),
+        None => state_dir.join(format!("{name}.json")),
+    }
 }
 
 impl Config {
-    pub fn ping_monitor_configs(&self) -> &HashMap<Target, PingMonitorConfig> {
-        &self.ping_monitors
+    pub async fn load(path: &Path) -> anyhow::Result<Config> {
+        let contents = smol::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Could not read config file {}: {e}", path.display()))?;
+
+        toml::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Invalid config file {}: {e}", path.display()))
     }
 
-    pub fn ping_monitors(&self) -> Vec<PingMonitor> {
-        self.ping_monitors
-            .iter()
-            .map(
-                |(
-                    target,
-                    PingMonitorConfig {
-                        interval_seconds,
-                        history_length_hours,
-                    },
-                )| {
-                    let history_length =
-                        Duration::from_secs_f32(history_length_hours * 60_f32 * 60_f32);
-                    PingMonitor::new(target.clone(), *interval_seconds, history_length)
-                },
-            )
-            .collect()
+    pub fn socket_path(&self) -> PathBuf {
+        self.socket_path
+            .clone()
+            .unwrap_or_else(|| default_socket_path(self.instance.as_deref()))
+    }
+
+    pub fn outbox_path(&self) -> PathBuf {
+        self.notifications
+            .outbox_path
+            .clone()
+            .unwrap_or_else(|| default_state_path(self.instance.as_deref(), "outbox"))
+    }
+
+    pub fn seasonal_baselines_path(&self) -> PathBuf {
+        self.seasonal_path
+            .clone()
+            .unwrap_or_else(|| default_state_path(self.instance.as_deref(), "seasonal"))
+    }
+
+    /* Carries over the settings that only take effect when the service
+     * starts, some of which may have been overridden on the command line.
+     * Ping monitors, alert rules, access lists and remote tokens are the
+     * ones a reload changes.
+     */
+    pub fn keep_startup_settings(&mut self, running: &Config) {
+        self.remove_existing_socket = running.remove_existing_socket;
+        self.socket_path.clone_from(&running.socket_path);
+        self.socket_mode = running.socket_mode;
+        self.socket_owner = running.socket_owner;
+        self.socket_group = running.socket_group;
+        self.instance.clone_from(&running.instance);
+        self.seasonal_path.clone_from(&running.seasonal_path);
+        self.query_server = running.query_server;
+        self.http.clone_from(&running.http);
+        self.notifications.clone_from(&running.notifications);
+        self.webhooks.clone_from(&running.webhooks);
+
+        let tokens = self
+            .remote
+            .take()
+            .map(|remote| remote.tokens)
+            .unwrap_or_default();
+        self.remote = running
+            .remote
+            .clone()
+            .map(|remote| RemoteConfig { tokens, ..remote });
+    }
+
+    pub fn ping_monitor_configs(&self) -> &HashMap<Target, PingMonitorConfig> {
+        &self.ping_monitors
     }
 }
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ping::ProbeError;
+use crate::server::ServerState;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MonitorDiagnostics {
+    pub paused: bool,
+    pub probe_running: bool,
+    pub probe_restarts: u64,
+    pub last_probe_error: Option<ProbeError>,
+    pub parse_failures: u64,
+    pub readings: usize,
+    pub history_memory_bytes: usize,
+}
+
+/* What the service knows about itself, for bug reports */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceDiagnostics {
+    pub version: String,
+    pub started_at: SystemTime,
+    pub uptime: Duration,
+    pub config_path: PathBuf,
+    pub socket_path: PathBuf,
+    pub remote_listen: Option<String>,
+    pub http_listen: Option<String>,
+    pub monitor_tasks: usize,
+    pub connected_clients: usize,
+    pub pending_notifications: usize,
+    pub monitors: HashMap<String, MonitorDiagnostics>,
+}
+
+pub fn collect_diagnostics(server_state: &ServerState) -> ServiceDiagnostics {
+    let monitors: HashMap<String, MonitorDiagnostics> = server_state
+        .monitors
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(target, monitored_target)| {
+            let history = monitored_target.reading_history.lock().unwrap();
+            let metrics = history.metrics();
+
+            (
+                target.clone(),
+                MonitorDiagnostics {
+                    paused: monitored_target.is_paused(),
+                    probe_running: history.is_probe_running(),
+                    probe_restarts: metrics.probe_restarts,
+                    last_probe_error: history.last_probe_error().cloned(),
+                    parse_failures: metrics.parse_failures,
+                    readings: history.reading_count(),
+                    history_memory_bytes: history.memory_usage(),
+                },
+            )
+        })
+        .collect();
+
+    ServiceDiagnostics {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        started_at: server_state.started_at,
+        uptime: server_state.started_at.elapsed().unwrap_or_default(),
+        config_path: server_state.config_path.clone(),
+        socket_path: server_state.config().socket_path(),
+        remote_listen: server_state
+            .config()
+            .remote
+            .as_ref()
+            .map(|remote| remote.listen.clone()),
+        http_listen: server_state
+            .config()
+            .http
+            .as_ref()
+            .map(|http| http.listen.clone()),
+        monitor_tasks: monitors.values().filter(|monitor| !monitor.paused).count(),
+        connected_clients: server_state.connected_clients.load(Ordering::Relaxed),
+        pending_notifications: server_state.notifier.lock().unwrap().pending(),
+        monitors,
+    }
+}
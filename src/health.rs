@@ -0,0 +1,138 @@
+use std::fmt::Display;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::PingMonitorConfig;
+use crate::ping::PingReading;
+use crate::stats::PingStatistics;
+
+/* How often the state of every target is re-evaluated */
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/* Targets with more loss than this over the recent window are degraded */
+pub const DEGRADED_LOSS_PERCENT: f64 = 10.0;
+
+const MIN_RECENT_WINDOW: Duration = Duration::from_secs(60);
+const MIN_DOWN_AFTER: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TargetState {
+    Up,
+    Degraded,
+    Down,
+    ProbeFailing,
+    Paused,
+}
+
+impl Display for TargetState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TargetState::Up => "up",
+            TargetState::Degraded => "degraded",
+            TargetState::Down => "down",
+            TargetState::ProbeFailing => "probe-failing",
+            TargetState::Paused => "paused",
+        })
+    }
+}
+
+/* Saturates rather than panicking, the interval is only bounded once the
+ * config has been validated.
+ */
+fn intervals(config: &PingMonitorConfig, count: f32) -> Duration {
+    Duration::try_from_secs_f32(config.interval_seconds * count).unwrap_or(Duration::MAX)
+}
+
+/* The readings that decide whether a target is degraded, at least ten
+ * intervals so that slow monitors still have a few readings to go on.
+ */
+pub fn recent_window(config: &PingMonitorConfig) -> Duration {
+    MIN_RECENT_WINDOW.max(intervals(config, 10.0))
+}
+
+/* A target is down once it has not replied for a few intervals */
+fn down_after(config: &PingMonitorConfig) -> Duration {
+    MIN_DOWN_AFTER.max(intervals(config, 3.0))
+}
+
+impl TargetState {
+    /* States in which the target is not producing readings */
+    pub fn is_down(&self) -> bool {
+        matches!(self, TargetState::Down | TargetState::ProbeFailing)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StateChange {
+    pub target: String,
+    pub from: TargetState,
+    pub to: TargetState,
+    pub time: SystemTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TargetStatus {
+    pub state: TargetState,
+    pub state_since: SystemTime,
+    pub last_reading_time: Option<SystemTime>,
+    pub last_latency: Option<Duration>,
+    pub recent_loss_percent: Option<f64>,
+}
+
+/* What the state of a target is decided from, `active_since` is when its
+ * monitor was last started so that a new target is not down before it had a
+ * chance to reply.
+ */
+pub struct HealthInputs<'a> {
+    pub config: &'a PingMonitorConfig,
+    pub paused: bool,
+    pub probe_running: bool,
+    pub active_since: SystemTime,
+    pub recent_readings: &'a [&'a PingReading],
+}
+
+impl HealthInputs<'_> {
+    pub fn state(&self, now: SystemTime) -> TargetState {
+        if self.paused {
+            return TargetState::Paused;
+        }
+
+        if !self.probe_running {
+            return TargetState::ProbeFailing;
+        }
+
+        let last_activity = self
+            .recent_readings
+            .last()
+            .map_or(self.active_since, |reading| {
+                reading.timestamp.max(self.active_since)
+            });
+        let quiet_for = now.duration_since(last_activity).unwrap_or_default();
+
+        if quiet_for > down_after(self.config) {
+            return TargetState::Down;
+        }
+
+        let Some(statistics) = PingStatistics::from_readings(self.recent_readings) else {
+            return TargetState::Up;
+        };
+
+        let over_sla = self
+            .config
+            .sla
+            .is_some_and(|sla| statistics.median_ms > sla.latency_threshold_ms as f64);
+
+        if statistics.loss_percent > DEGRADED_LOSS_PERCENT || over_sla {
+            TargetState::Degraded
+        } else {
+            TargetState::Up
+        }
+    }
+
+    pub fn recent_loss_percent(&self) -> Option<f64> {
+        PingStatistics::from_readings(self.recent_readings)
+            .map(|statistics| statistics.loss_percent)
+    }
+}
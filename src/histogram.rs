@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+use serde::{Deserialize, Serialize};
+
+use crate::ping::PingReading;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistogramBucket {
+    pub lower: Duration,
+    pub upper: Option<Duration>,
+    pub count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LatencyHistogram {
+    pub buckets: Vec<HistogramBucket>,
+    pub total: usize,
+}
+
+impl LatencyHistogram {
+    /* Buckets are half open, `[lower, upper)`, with the last bucket having
+     * no upper bound so that every reading is counted.
+     */
+    pub fn from_readings(readings: &[&PingReading], boundaries: &[Duration]) -> LatencyHistogram {
+        let mut boundaries = boundaries.to_vec();
+        boundaries.sort();
+        boundaries.dedup();
+
+        let lower_bounds = std::iter::once(Duration::ZERO).chain(boundaries.iter().copied());
+        let upper_bounds = boundaries.iter().copied().map(Some).chain([None]);
+
+        let mut buckets: Vec<HistogramBucket> = lower_bounds
+            .zip(upper_bounds)
+            .map(|(lower, upper)| HistogramBucket {
+                lower,
+                upper,
+                count: 0,
+            })
+            .collect();
+
+        for reading in readings {
+            let bucket_index = boundaries.partition_point(|boundary| *boundary <= reading.latency);
+            buckets[bucket_index].count += 1;
+        }
+
+        LatencyHistogram {
+            buckets,
+            total: readings.len(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct HeatmapCell {
+    pub count: usize,
+    pub mean_ms: Option<f64>,
+}
+
+/* Mean latency by local day of week and hour of day, `cells[0]` is monday
+ * and `cells[day][0]` is the hour starting at midnight.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LatencyHeatmap {
+    pub cells: Vec<Vec<HeatmapCell>>,
+}
+
+impl LatencyHeatmap {
+    pub fn from_readings(readings: &[&PingReading]) -> LatencyHeatmap {
+        let mut sums = vec![vec![(0_usize, 0_f64); 24]; 7];
+
+        for reading in readings {
+            let time = DateTime::<Local>::from(reading.timestamp);
+            let day = time.weekday().num_days_from_monday() as usize;
+            let hour = time.hour() as usize;
+
+            let (count, sum) = &mut sums[day][hour];
+            *count += 1;
+            *sum += reading.latency.as_secs_f64() * 1000.0;
+        }
+
+        LatencyHeatmap {
+            cells: sums
+                .into_iter()
+                .map(|hours| {
+                    hours
+                        .into_iter()
+                        .map(|(count, sum)| HeatmapCell {
+                            count,
+                            mean_ms: (count > 0).then(|| sum / count as f64),
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
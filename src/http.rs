@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::json;
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use smol::lock::Semaphore;
+use smol::net::{TcpListener, TcpStream};
+use smol::stream::StreamExt;
+
+use crate::alerts::AlertRule;
+use crate::client::ClientCommand;
+use crate::config::{Config, HttpConfig, PingMonitorConfig, QueryServerConfig};
+use crate::metrics::render_metrics;
+use crate::notify::NotificationsConfig;
+use crate::ping::PingReadingQuery;
+use crate::server::{
+    respond_to_command, ServerError, ServerResponse, ServerState, TargetAndPingReadingQuery,
+    TargetAndStatisticsQuery,
+};
+use crate::stats::TimeRange;
+use crate::util::with_timeout;
+
+const MAX_REQUEST_HEADER_SIZE: usize = 16 * 1024;
+const MAX_REQUEST_HEADERS: usize = 64;
+
+struct HttpResponse {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn json<T: Serialize>(value: &T) -> HttpResponse {
+        match serde_json::to_vec_pretty(value) {
+            Ok(body) => HttpResponse {
+                status: 200,
+                reason: "OK",
+                content_type: "application/json",
+                body,
+            },
+            Err(e) => HttpResponse::error(500, "Internal Server Error", &e.to_string()),
+        }
+    }
+
+    fn text(content_type: &'static str, body: String) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            reason: "OK",
+            content_type,
+            body: body.into_bytes(),
+        }
+    }
+
+    fn error(status: u16, reason: &'static str, message: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            reason,
+            content_type: "application/json",
+            body: json!({ "error": message }).to_string().into_bytes(),
+        }
+    }
+
+    fn bad_request(message: &str) -> HttpResponse {
+        HttpResponse::error(400, "Bad Request", message)
+    }
+
+    fn not_found(message: &str) -> HttpResponse {
+        HttpResponse::error(404, "Not Found", message)
+    }
+
+    async fn write_to(&self, stream: &mut TcpStream) -> std::io::Result<()> {
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason,
+            self.content_type,
+            self.body.len()
+        );
+
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&self.body).await?;
+        stream.flush().await
+    }
+}
+
+type QueryParameters = HashMap<String, String>;
+
+fn parse_parameter<T: std::str::FromStr>(
+    parameters: &QueryParameters,
+    name: &str,
+) -> Result<Option<T>, HttpResponse> {
+    parameters
+        .get(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| HttpResponse::bad_request(&format!("invalid value for '{name}'")))
+        })
+        .transpose()
+}
+
+fn require_parameter<T: std::str::FromStr>(
+    parameters: &QueryParameters,
+    name: &str,
+) -> Result<T, HttpResponse> {
+    parse_parameter(parameters, name)?
+        .ok_or_else(|| HttpResponse::bad_request(&format!("missing parameter '{name}'")))
+}
+
+/* `since` and `until` are how many seconds ago the range starts and ends, the
+ * same as the CLI.
+ */
+fn range_parameters(parameters: &QueryParameters) -> Result<TimeRange, HttpResponse> {
+    let since: Option<u64> = parse_parameter(parameters, "since")?;
+    let until: Option<u64> = parse_parameter(parameters, "until")?;
+
+    Ok(TimeRange::relative_to_now(
+        since.map(Duration::from_secs),
+        until.map(Duration::from_secs),
+    ))
+}
+
+fn targets(server_state: &ServerState) -> HttpResponse {
+    let targets: HashMap<String, PingMonitorConfig> = server_state
+        .monitors
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(target, monitored_target)| (target.clone(), monitored_target.config))
+        .collect();
+
+    HttpResponse::json(&targets)
+}
+
+fn readings(
+    parameters: &QueryParameters,
+    server_state: &ServerState,
+) -> Result<HttpResponse, HttpResponse> {
+    let target = parameters.get("target").cloned();
+    let latency_higher_than: u64 = require_parameter(parameters, "latency-higher-than")?;
+    let min_intensity: u32 = require_parameter(parameters, "min-intensity")?;
+    let max_window: u64 = require_parameter(parameters, "max-window")?;
+
+    let query = PingReadingQuery::new(
+        Duration::from_millis(latency_higher_than),
+        min_intensity,
+        Duration::from_secs(max_window),
+    );
+
+    match respond_to_command(
+        ClientCommand::TargetAndPingReadingQuery(TargetAndPingReadingQuery { target, query }),
+        server_state,
+    ) {
+        ServerResponse::PingQueryResult(results) => Ok(HttpResponse::json(
+            &results
+                .into_iter()
+                .map(|(target, (episodes, config))| {
+                    (target, json!({ "episodes": episodes, "config": config }))
+                })
+                .collect::<HashMap<_, _>>(),
+        )),
+        response => Ok(unexpected_response(response)),
+    }
+}
+
+fn statistics(
+    parameters: &QueryParameters,
+    server_state: &ServerState,
+) -> Result<HttpResponse, HttpResponse> {
+    let target = parameters.get("target").cloned();
+    let range = range_parameters(parameters)?;
+
+    match respond_to_command(
+        ClientCommand::TargetAndStatisticsQuery(TargetAndStatisticsQuery { target, range }),
+        server_state,
+    ) {
+        ServerResponse::StatisticsResult(results) => Ok(HttpResponse::json(&results)),
+        response => Ok(unexpected_response(response)),
+    }
+}
+
+/* What the unauthenticated API shows of the config. Fields are listed one by
+ * one rather than removing secrets from the whole config, so that settings
+ * added later are not served until they are added here. Ping monitors and
+ * alert rules are the running ones, which include targets added at runtime.
+ */
+#[derive(Serialize)]
+struct PublicConfig<'a> {
+    #[serde(rename = "ping-monitors")]
+    ping_monitors: HashMap<String, PingMonitorConfig>,
+    instance: Option<&'a str>,
+    #[serde(rename = "socket-path")]
+    socket_path: PathBuf,
+    #[serde(rename = "query-server")]
+    query_server: &'a QueryServerConfig,
+    remote: Option<PublicRemoteConfig<'a>>,
+    http: Option<&'a HttpConfig>,
+    alerts: HashMap<String, AlertRule>,
+    notifications: &'a NotificationsConfig,
+    webhooks: HashMap<&'a str, PublicWebhookConfig<'a>>,
+}
+
+/* URLs and headers of webhooks often hold credentials */
+#[derive(Serialize)]
+struct PublicWebhookConfig<'a> {
+    rules: &'a [String],
+    templated: bool,
+}
+
+#[derive(Serialize)]
+struct PublicRemoteConfig<'a> {
+    listen: &'a str,
+    #[serde(rename = "client-certificates")]
+    client_certificates: bool,
+}
+
+impl<'a> PublicConfig<'a> {
+    fn new(config: &'a Config, server_state: &ServerState) -> Self {
+        PublicConfig {
+            ping_monitors: server_state
+                .monitors
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(target, monitored)| (target.clone(), monitored.config))
+                .collect(),
+            instance: config.instance.as_deref(),
+            socket_path: config.socket_path(),
+            query_server: &config.query_server,
+            remote: config.remote.as_ref().map(|remote| PublicRemoteConfig {
+                listen: &remote.listen,
+                client_certificates: remote.client_ca.is_some(),
+            }),
+            http: config.http.as_ref(),
+            alerts: server_state.alerts.lock().unwrap().rules().clone(),
+            notifications: &config.notifications,
+            webhooks: config
+                .webhooks
+                .iter()
+                .map(|(name, webhook)| {
+                    (
+                        name.as_str(),
+                        PublicWebhookConfig {
+                            rules: &webhook.rules,
+                            templated: webhook.template.is_some(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+fn config(server_state: &ServerState) -> HttpResponse {
+    let config = server_state.config();
+    HttpResponse::json(&PublicConfig::new(&config, server_state))
+}
+
+fn unexpected_response(response: ServerResponse) -> HttpResponse {
+    let ServerResponse::Error(error) = response else {
+        return HttpResponse::error(500, "Internal Server Error", "unexpected query result");
+    };
+
+    let message = error.to_string();
+    match error {
+        ServerError::UnknownTarget(_) => HttpResponse::not_found(&message),
+        ServerError::InvalidQuery(_) => HttpResponse::bad_request(&message),
+        ServerError::Internal(_) => HttpResponse::error(500, "Internal Server Error", &message),
+        ServerError::UnsupportedCommand(_) => HttpResponse::error(501, "Not Implemented", &message),
+        ServerError::PermissionDenied(_) => HttpResponse::error(403, "Forbidden", &message),
+        ServerError::InvalidTarget(_) => HttpResponse::bad_request(&message),
+    }
+}
+
+fn route(
+    method: &str,
+    path: &str,
+    parameters: &QueryParameters,
+    server_state: &ServerState,
+) -> HttpResponse {
+    if method != "GET" {
+        return HttpResponse::error(405, "Method Not Allowed", "only GET is supported");
+    }
+
+    let response = match path.trim_end_matches('/') {
+        "/targets" => Ok(targets(server_state)),
+        "/readings" => readings(parameters, server_state),
+        "/stats" => statistics(parameters, server_state),
+        "/config" => Ok(config(server_state)),
+        "/metrics"
+            if server_state
+                .config()
+                .http
+                .as_ref()
+                .is_some_and(|http| http.metrics) =>
+        {
+            Ok(HttpResponse::text(
+                "text/plain; version=0.0.4",
+                render_metrics(server_state),
+            ))
+        }
+        _ => Err(HttpResponse::not_found(&format!("no such endpoint {path}"))),
+    };
+
+    response.unwrap_or_else(|error| error)
+}
+
+/* Reads until the end of the request headers, request bodies are never used
+ * so they are not read.
+ */
+async fn read_request_head(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+
+    while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_HEADER_SIZE {
+            anyhow::bail!("Request headers are larger than {MAX_REQUEST_HEADER_SIZE} bytes");
+        }
+
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            anyhow::bail!("Connection closed before the request was complete");
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+
+    Ok(buf)
+}
+
+async fn serve_http_client(
+    mut stream: TcpStream,
+    server_state: &Arc<ServerState>,
+) -> anyhow::Result<()> {
+    let read_timeout = server_state.config().query_server.read_timeout();
+
+    let head = with_timeout(read_timeout, "the HTTP request", async {
+        read_request_head(&mut stream).await
+    })
+    .await?;
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+
+    let response = match request.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => {
+            let method = request.method.unwrap_or_default();
+            let (path, query) = request
+                .path
+                .unwrap_or_default()
+                .split_once('?')
+                .unwrap_or((request.path.unwrap_or_default(), ""));
+
+            let parameters: QueryParameters = form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect();
+
+            log::debug!("HTTP {method} {path}");
+
+            /* Like queries over the socket, kept off the executor the ping
+             * monitors run on.
+             */
+            let (method, path) = (method.to_string(), path.to_string());
+            let server_state = server_state.clone();
+            smol::unblock(move || route(&method, &path, &parameters, &server_state)).await
+        }
+        Ok(httparse::Status::Partial) | Err(_) => {
+            HttpResponse::bad_request("could not parse request")
+        }
+    };
+
+    with_timeout(
+        read_timeout,
+        "the HTTP client to accept the response",
+        async { Ok(response.write_to(&mut stream).await?) },
+    )
+    .await
+}
+
+/* The HTTP API is read only and unauthenticated, it should only listen on
+ * addresses that are trusted.
+ */
+pub async fn serve_http_api(
+    http_config: HttpConfig,
+    server_state: Arc<ServerState>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&http_config.listen).await?;
+    log::info!("Listening for HTTP requests on {}", http_config.listen);
+
+    let connection_slots = Arc::new(Semaphore::new(
+        server_state.config().query_server.max_connections,
+    ));
+
+    let mut incoming = listener.incoming();
+
+    while let Some(stream) = incoming.next().await {
+        let stream = stream?;
+
+        let Some(connection_slot) = connection_slots.try_acquire_arc() else {
+            log::warn!("Rejecting HTTP client, too many connections are open");
+            continue;
+        };
+
+        let server_state = server_state.clone();
+        smol::spawn(async move {
+            if let Err(e) = serve_http_client(stream, &server_state).await {
+                log::error!("Encountered error serving HTTP client: {e}");
+            }
+            drop(connection_slot);
+        })
+        .detach();
+    }
+
+    Ok(())
+}
@@ -1,28 +1,104 @@
 use std::{path::PathBuf, time::Duration};
 
+use anomaly::AnomalyConfig;
 use clap::{Parser, Subcommand};
 use client::{
-    display_ping_query_results, send_client_command, ClientCommand, PingQueryResultDisplayOptions,
+    display_alert_report, display_anomaly_results, display_comparison_results, display_diagnostics,
+    display_heatmap_results, display_histogram_results, display_level_shift_results,
+    display_ping_query_results, display_sla_results, display_statistics_results,
+    display_status_results, send_client_command, watch_subscription, ClientCommand,
+    PingQueryResultDisplayOptions, ServiceAddress,
 };
+use config::PingMonitorConfig;
 use ping::PingReadingQuery;
-use server::{ServerResponse, TargetAndPingReadingQuery};
-use smol::io::AsyncReadExt;
+use remote::RemoteOptions;
+use server::{
+    ServerResponse, TargetAndAlertQuery, TargetAndAnomalyQuery, TargetAndComparisonQuery,
+    TargetAndHeatmapQuery, TargetAndHistogramQuery, TargetAndLevelShiftQuery,
+    TargetAndMonitorConfig, TargetAndPingReadingQuery, TargetAndRelativePingReadingQuery,
+    TargetAndSeasonalBaselineQuery, TargetAndSlaQuery, TargetAndStatisticsQuery,
+    TargetAndStatusQuery, TargetSubscription,
+};
+use sla::{SlaConfig, SlaPeriod};
 use smol_macros::main;
+use stats::TimeRange;
 
+mod access;
+mod alerts;
 mod anomaly;
+mod changepoint;
 mod client;
 mod command_watcher;
 mod config;
+mod diagnostics;
+mod health;
+mod histogram;
+mod http;
+mod metrics;
 mod monitor;
+mod notify;
 mod ping;
+mod protocol;
+mod remote;
+mod seasonal;
 mod server;
 mod service;
+mod sla;
+mod stats;
+mod subscription;
 mod util;
 
-pub const UNIX_SOCKET_PATH: &str = "/tmp/oxidenet";
-
 #[derive(Parser, Debug)]
 struct Args {
+    #[arg(
+        long,
+        global = true,
+        env = "OXIDENET_SOCKET",
+        help = "path of the service socket, overrides the instance and config"
+    )]
+    socket: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        env = "OXIDENET_INSTANCE",
+        help = "name of the service instance, each instance has its own socket and state files"
+    )]
+    instance: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        env = "OXIDENET_REMOTE",
+        help = "address of a remote service to connect to over TLS, such as monitor.example.com:7878"
+    )]
+    remote: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        env = "OXIDENET_TOKEN",
+        help = "token to authenticate with the remote service"
+    )]
+    token: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        env = "OXIDENET_CA_CERTIFICATE",
+        help = "CA certificate used to verify the remote service"
+    )]
+    ca_certificate: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        help = "client certificate to authenticate with the remote service"
+    )]
+    client_certificate: Option<PathBuf>,
+    #[arg(long, global = true, help = "private key of the client certificate")]
+    client_key: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        help = "name to verify the remote service certificate against, defaults to the host of the remote address"
+    )]
+    server_name: Option<String>,
     #[command(subcommand)]
     command: Command,
 }
@@ -65,6 +141,231 @@ pub enum Query {
             help = "show the original lines from the ping utility"
         )]
         show_original_line: bool,
+        #[arg(
+            long,
+            short = 'e',
+            help = "show the readings of the episode with the given index"
+        )]
+        expand: Option<usize>,
+        #[arg(long, short = 'a', help = "show the readings of every episode")]
+        expand_all: bool,
+        #[arg(
+            long,
+            short = 'x',
+            help = "count readings over this many times the usual latency for their hour of the week instead, the latency threshold is used for hours not seen yet"
+        )]
+        times_usual: Option<f64>,
+    },
+    #[clap(about = "query latency and loss statistics")]
+    Stats {
+        #[arg(long, short, help = "filter by target, optional")]
+        target: Option<String>,
+        #[arg(
+            long,
+            short,
+            help = "only include readings from the last N seconds, optional"
+        )]
+        since: Option<u32>,
+        #[arg(
+            long,
+            short,
+            help = "exclude readings from the last N seconds, optional"
+        )]
+        until: Option<u32>,
+        #[arg(long, short, help = "output the statistics as JSON")]
+        json: bool,
+    },
+    #[clap(about = "compare statistics of a period against an earlier one")]
+    Compare {
+        #[arg(long, short, help = "filter by target, optional")]
+        target: Option<String>,
+        #[arg(long, short, help = "start of the current period, N seconds ago")]
+        since: u32,
+        #[arg(
+            long,
+            short,
+            help = "end of the current period, N seconds ago, defaults to now"
+        )]
+        until: Option<u32>,
+        #[arg(
+            long,
+            short,
+            default_value_t = 24 * 60 * 60,
+            help = "how far back the earlier period is from the current one, in seconds, defaults to a day"
+        )]
+        offset: u32,
+        #[arg(long, short, help = "output the comparison as JSON")]
+        json: bool,
+    },
+    #[clap(about = "query a latency histogram")]
+    Histogram {
+        #[arg(long, short, help = "filter by target, optional")]
+        target: Option<String>,
+        #[arg(
+            long,
+            short,
+            help = "only include readings from the last N seconds, optional"
+        )]
+        since: Option<u32>,
+        #[arg(
+            long,
+            short,
+            help = "exclude readings from the last N seconds, optional"
+        )]
+        until: Option<u32>,
+        #[arg(
+            long,
+            short,
+            value_delimiter = ',',
+            default_value = "10,20,50,100,200,500,1000",
+            help = "bucket boundaries, comma separated list, in ms"
+        )]
+        buckets: Vec<u32>,
+        #[arg(long, short, help = "output the histogram as JSON")]
+        json: bool,
+    },
+    #[clap(about = "query mean latency by day of week and hour of day")]
+    Heatmap {
+        #[arg(long, short, help = "filter by target, optional")]
+        target: Option<String>,
+        #[arg(
+            long,
+            short,
+            help = "only include readings from the last N seconds, optional"
+        )]
+        since: Option<u32>,
+        #[arg(
+            long,
+            short,
+            help = "exclude readings from the last N seconds, optional"
+        )]
+        until: Option<u32>,
+        #[arg(
+            long,
+            help = "show the usual latency learned for each hour of the week instead of readings in range"
+        )]
+        usual: bool,
+        #[arg(long, short, help = "output the heatmap as JSON")]
+        json: bool,
+    },
+    #[clap(about = "list latency and loss anomalies detected against each target's baseline")]
+    Anomalies {
+        #[arg(long, short, help = "filter by target, optional")]
+        target: Option<String>,
+        #[arg(
+            long,
+            short,
+            help = "only include anomalies from the last N seconds, optional"
+        )]
+        since: Option<u32>,
+        #[arg(
+            long,
+            short,
+            help = "exclude anomalies from the last N seconds, optional"
+        )]
+        until: Option<u32>,
+        #[arg(long, short, help = "output the anomalies as JSON")]
+        json: bool,
+    },
+    #[clap(about = "list lasting changes in each target's latency")]
+    LevelShifts {
+        #[arg(long, short, help = "filter by target, optional")]
+        target: Option<String>,
+        #[arg(
+            long,
+            short,
+            help = "only include level shifts from the last N seconds, optional"
+        )]
+        since: Option<u32>,
+        #[arg(
+            long,
+            short,
+            help = "exclude level shifts from the last N seconds, optional"
+        )]
+        until: Option<u32>,
+        #[arg(
+            long,
+            short,
+            help = "show the readings each level shift was detected from"
+        )]
+        readings: bool,
+        #[arg(long, short, help = "output the level shifts as JSON")]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Report {
+    #[clap(about = "report availability against the configured SLA thresholds")]
+    Sla {
+        #[arg(long, short, help = "filter by target, optional")]
+        target: Option<String>,
+        #[arg(
+            long,
+            short,
+            help = "only include readings from the last N seconds, optional"
+        )]
+        since: Option<u32>,
+        #[arg(
+            long,
+            short,
+            help = "exclude readings from the last N seconds, optional"
+        )]
+        until: Option<u32>,
+        #[arg(
+            long,
+            short,
+            value_enum,
+            default_value_t = SlaPeriod::Day,
+            help = "the calendar period to summarize by, in the local timezone"
+        )]
+        period: SlaPeriod,
+        #[arg(long, short, help = "output the report as JSON")]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Target {
+    #[clap(about = "start monitoring a target")]
+    Add {
+        #[arg(help = "host name or address to ping")]
+        target: String,
+        #[arg(long, short, help = "time between pings, in seconds")]
+        interval_seconds: f32,
+        #[arg(long, short = 'l', help = "how long readings are kept, in hours")]
+        history_length_hours: f32,
+        #[arg(
+            long,
+            help = "latency over which a reading counts against the SLA, in ms, optional"
+        )]
+        sla_latency_threshold_ms: Option<f32>,
+        #[arg(
+            long,
+            requires = "sla_latency_threshold_ms",
+            help = "length of the intervals the SLA is measured in, in seconds, at least 1, optional"
+        )]
+        sla_interval_seconds: Option<f32>,
+        #[arg(
+            long,
+            help = "how many deviations from the baseline count as an anomaly, optional"
+        )]
+        anomaly_sensitivity: Option<f64>,
+    },
+    #[clap(about = "stop monitoring a target and drop its readings")]
+    Remove {
+        #[arg(help = "the target to remove")]
+        target: String,
+    },
+    #[clap(about = "stop pinging a target while keeping its readings")]
+    Pause {
+        #[arg(help = "the target to pause")]
+        target: String,
+    },
+    #[clap(about = "start pinging a paused target again")]
+    Resume {
+        #[arg(help = "the target to resume")]
+        target: String,
     },
 }
 
@@ -82,9 +383,85 @@ enum Command {
         #[command(subcommand)]
         query: Query,
     },
+    #[clap(about = "watch ping readings live as they arrive")]
+    Watch {
+        #[arg(long, short, help = "filter by target, optional")]
+        target: Option<String>,
+        #[arg(
+            long,
+            short = 'f',
+            help = "a format string for the time, the format is defined by chrono::format::strftime"
+        )]
+        time_format: Option<String>,
+        #[arg(
+            long,
+            short = 'o',
+            help = "show the original lines from the ping utility"
+        )]
+        show_original_line: bool,
+    },
+    #[clap(about = "generate reports from the monitor service")]
+    Report {
+        #[command(subcommand)]
+        report: Report,
+    },
+    #[clap(about = "show whether each target is up, degraded, down, failing or paused")]
+    Status {
+        #[arg(long, short, help = "filter by target, optional")]
+        target: Option<String>,
+        #[arg(long, short, help = "output the status as JSON")]
+        json: bool,
+    },
+    #[clap(about = "show active alerts and the alerts that fired or resolved")]
+    Alerts {
+        #[arg(long, short, help = "filter by target, optional")]
+        target: Option<String>,
+        #[arg(
+            long,
+            short,
+            help = "only include alert history from the last N seconds, optional"
+        )]
+        since: Option<u32>,
+        #[arg(
+            long,
+            short,
+            help = "exclude alert history from the last N seconds, optional"
+        )]
+        until: Option<u32>,
+        #[arg(long, short, help = "output the alerts as JSON")]
+        json: bool,
+    },
+    #[clap(about = "show the internal state of the monitor service, for bug reports")]
+    Diagnostics {
+        #[arg(long, short, help = "output the diagnostics as JSON")]
+        json: bool,
+    },
+    #[clap(about = "change the targets of a running monitor service")]
+    Target {
+        #[command(subcommand)]
+        target: Target,
+    },
+}
+
+fn service_address(args: &Args) -> ServiceAddress {
+    match &args.remote {
+        Some(address) => ServiceAddress::Remote(RemoteOptions {
+            address: address.clone(),
+            server_name: args.server_name.clone(),
+            ca_certificate: args.ca_certificate.clone(),
+            client_certificate: args.client_certificate.clone(),
+            client_key: args.client_key.clone(),
+            token: args.token.clone(),
+        }),
+        None => ServiceAddress::Local(
+            args.socket
+                .clone()
+                .unwrap_or_else(|| config::default_socket_path(args.instance.as_deref())),
+        ),
+    }
 }
 
-fn run_query(query: Query) -> anyhow::Result<()> {
+fn run_query(query: Query, address: &ServiceAddress) -> anyhow::Result<()> {
     match query {
         Query::Ping {
             target,
@@ -94,6 +471,9 @@ fn run_query(query: Query) -> anyhow::Result<()> {
             display_skip_warning_threshold,
             time_format,
             show_original_line,
+            expand,
+            expand_all,
+            times_usual,
         } => {
             let query = PingReadingQuery::new(
                 Duration::from_millis(latency_higher_than.into()),
@@ -101,53 +481,368 @@ fn run_query(query: Query) -> anyhow::Result<()> {
                 Duration::from_secs(max_window.into()),
             );
 
-            let server_response = send_client_command(ClientCommand::TargetAndPingReadingQuery(
-                TargetAndPingReadingQuery { target, query },
-            ))?;
+            let command = match times_usual {
+                Some(times_usual) => ClientCommand::TargetAndRelativePingReadingQuery(
+                    TargetAndRelativePingReadingQuery {
+                        target,
+                        query,
+                        times_usual,
+                    },
+                ),
+                None => ClientCommand::TargetAndPingReadingQuery(TargetAndPingReadingQuery {
+                    target,
+                    query,
+                }),
+            };
+
+            let server_response = send_client_command(address, command)?;
 
             let display_options = PingQueryResultDisplayOptions {
                 display_skip_warning_threshold: display_skip_warning_threshold
                     .map(|seconds| Duration::from_secs(seconds.into())),
                 time_format,
                 show_original_line,
+                expand_episode: expand,
+                expand_all_episodes: expand_all,
             };
 
             match server_response {
                 ServerResponse::PingQueryResult(results) => {
                     display_ping_query_results(&results, &display_options);
                 }
-                ServerResponse::UnknownTarget(target) => {
-                    println!("Server reply: Unknown target {target}");
+                _ => anyhow::bail!("Unexpected server response"),
+            }
+        }
+        Query::Stats {
+            target,
+            since,
+            until,
+            json,
+        } => {
+            let range = TimeRange::relative_to_now(
+                since.map(|seconds| Duration::from_secs(seconds.into())),
+                until.map(|seconds| Duration::from_secs(seconds.into())),
+            );
+
+            let server_response = send_client_command(
+                address,
+                ClientCommand::TargetAndStatisticsQuery(TargetAndStatisticsQuery { target, range }),
+            )?;
+
+            match server_response {
+                ServerResponse::StatisticsResult(results) => {
+                    display_statistics_results(&results, json)?;
+                }
+                _ => anyhow::bail!("Unexpected server response"),
+            }
+        }
+        Query::Compare {
+            target,
+            since,
+            until,
+            offset,
+            json,
+        } => {
+            let second = TimeRange::relative_to_now(
+                Some(Duration::from_secs(since.into())),
+                Some(Duration::from_secs(until.unwrap_or(0).into())),
+            );
+            let first = second.shifted_back(Duration::from_secs(offset.into()));
+
+            let server_response = send_client_command(
+                address,
+                ClientCommand::TargetAndComparisonQuery(TargetAndComparisonQuery {
+                    target,
+                    first,
+                    second,
+                }),
+            )?;
+
+            match server_response {
+                ServerResponse::ComparisonResult(results) => {
+                    display_comparison_results(&results, json)?;
+                }
+                _ => anyhow::bail!("Unexpected server response"),
+            }
+        }
+        Query::Histogram {
+            target,
+            since,
+            until,
+            buckets,
+            json,
+        } => {
+            let range = TimeRange::relative_to_now(
+                since.map(|seconds| Duration::from_secs(seconds.into())),
+                until.map(|seconds| Duration::from_secs(seconds.into())),
+            );
+            let bucket_boundaries = buckets
+                .into_iter()
+                .map(|ms| Duration::from_millis(ms.into()))
+                .collect();
+
+            let server_response = send_client_command(
+                address,
+                ClientCommand::TargetAndHistogramQuery(TargetAndHistogramQuery {
+                    target,
+                    range,
+                    bucket_boundaries,
+                }),
+            )?;
+
+            match server_response {
+                ServerResponse::HistogramResult(results) => {
+                    display_histogram_results(&results, json)?;
+                }
+                _ => anyhow::bail!("Unexpected server response"),
+            }
+        }
+        Query::Heatmap {
+            target,
+            since,
+            until,
+            usual,
+            json,
+        } => {
+            let range = TimeRange::relative_to_now(
+                since.map(|seconds| Duration::from_secs(seconds.into())),
+                until.map(|seconds| Duration::from_secs(seconds.into())),
+            );
+
+            let command = if usual {
+                ClientCommand::TargetAndSeasonalBaselineQuery(TargetAndSeasonalBaselineQuery {
+                    target,
+                })
+            } else {
+                ClientCommand::TargetAndHeatmapQuery(TargetAndHeatmapQuery { target, range })
+            };
+
+            let server_response = send_client_command(address, command)?;
+
+            match server_response {
+                ServerResponse::HeatmapResult(results) => {
+                    display_heatmap_results(&results, json)?;
                 }
+                _ => anyhow::bail!("Unexpected server response"),
+            }
+        }
+        Query::Anomalies {
+            target,
+            since,
+            until,
+            json,
+        } => {
+            let range = TimeRange::relative_to_now(
+                since.map(|seconds| Duration::from_secs(seconds.into())),
+                until.map(|seconds| Duration::from_secs(seconds.into())),
+            );
+
+            let server_response = send_client_command(
+                address,
+                ClientCommand::TargetAndAnomalyQuery(TargetAndAnomalyQuery { target, range }),
+            )?;
+
+            match server_response {
+                ServerResponse::AnomalyResult(results) => {
+                    display_anomaly_results(&results, json)?;
+                }
+                _ => anyhow::bail!("Unexpected server response"),
+            }
+        }
+        Query::LevelShifts {
+            target,
+            since,
+            until,
+            readings,
+            json,
+        } => {
+            let range = TimeRange::relative_to_now(
+                since.map(|seconds| Duration::from_secs(seconds.into())),
+                until.map(|seconds| Duration::from_secs(seconds.into())),
+            );
+
+            let server_response = send_client_command(
+                address,
+                ClientCommand::TargetAndLevelShiftQuery(TargetAndLevelShiftQuery {
+                    target,
+                    range,
+                    include_readings: readings,
+                }),
+            )?;
+
+            match server_response {
+                ServerResponse::LevelShiftResult(results) => {
+                    display_level_shift_results(&results, json)?;
+                }
+                _ => anyhow::bail!("Unexpected server response"),
             }
         }
     }
     Ok(())
 }
 
+fn run_report(report: Report, address: &ServiceAddress) -> anyhow::Result<()> {
+    match report {
+        Report::Sla {
+            target,
+            since,
+            until,
+            period,
+            json,
+        } => {
+            let range = TimeRange::relative_to_now(
+                since.map(|seconds| Duration::from_secs(seconds.into())),
+                until.map(|seconds| Duration::from_secs(seconds.into())),
+            );
+
+            let server_response = send_client_command(
+                address,
+                ClientCommand::TargetAndSlaQuery(TargetAndSlaQuery {
+                    target,
+                    range,
+                    period,
+                }),
+            )?;
+
+            match server_response {
+                ServerResponse::SlaResult(results) => {
+                    display_sla_results(&results, json)?;
+                }
+                _ => anyhow::bail!("Unexpected server response"),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_target(target: Target, address: &ServiceAddress) -> anyhow::Result<()> {
+    let (command, action) = match target {
+        Target::Add {
+            target,
+            interval_seconds,
+            history_length_hours,
+            sla_latency_threshold_ms,
+            sla_interval_seconds,
+            anomaly_sensitivity,
+        } => {
+            let sla = sla_latency_threshold_ms.map(|latency_threshold_ms| SlaConfig {
+                latency_threshold_ms,
+                interval_seconds: sla_interval_seconds
+                    .unwrap_or(SlaConfig::default().interval_seconds),
+            });
+
+            (
+                ClientCommand::AddTarget(TargetAndMonitorConfig {
+                    target,
+                    config: PingMonitorConfig {
+                        interval_seconds,
+                        history_length_hours,
+                        sla,
+                        anomaly: AnomalyConfig {
+                            sensitivity: anomaly_sensitivity
+                                .unwrap_or(AnomalyConfig::default().sensitivity),
+                            ..AnomalyConfig::default()
+                        },
+                    },
+                }),
+                "Added",
+            )
+        }
+        Target::Remove { target } => (ClientCommand::RemoveTarget(target), "Removed"),
+        Target::Pause { target } => (ClientCommand::PauseTarget(target), "Paused"),
+        Target::Resume { target } => (ClientCommand::ResumeTarget(target), "Resumed"),
+    };
+
+    match send_client_command(address, command)? {
+        ServerResponse::TargetUpdated(target) => println!("{action} {target}"),
+        _ => anyhow::bail!("Unexpected server response"),
+    }
+    Ok(())
+}
+
 main! {
     async fn main() -> anyhow::Result<()> {
         env_logger::init();
 
         let args = Args::parse();
+        let address = service_address(&args);
 
         match args.command {
-            Command::Service { config, remove_existing_socket } => {
+            Command::Service { config: config_path, remove_existing_socket } => {
 
-                let mut buf = String::new();
-                let mut config_file = smol::fs::File::open(config).await?;
-                config_file.read_to_string(&mut buf).await?;
-
-                let mut config: config::Config = toml::from_str(&buf)?;
+                let mut config = config::Config::load(&config_path).await?;
 
                 if remove_existing_socket {
                     config.remove_existing_socket = Some(remove_existing_socket);
                 }
 
-                service::run_service(config).await
+                if let Some(socket) = args.socket {
+                    config.socket_path = Some(socket);
+                } else if let Some(instance) = &args.instance {
+                    config.socket_path = Some(config::default_socket_path(Some(instance)));
+                }
+
+                /* The instance also names the files the service keeps state in */
+                if let Some(instance) = args.instance {
+                    config.instance = Some(instance);
+                }
+
+                service::run_service(config, config_path).await
             },
             Command::Query { query } => {
-                run_query(query)
+                run_query(query, &address)
+            },
+            Command::Watch { target, time_format, show_original_line } => {
+                let display_options = PingQueryResultDisplayOptions {
+                    display_skip_warning_threshold: None,
+                    time_format,
+                    show_original_line,
+                    expand_episode: None,
+                    expand_all_episodes: false,
+                };
+
+                watch_subscription(
+                    &address,
+                    TargetSubscription { target },
+                    &display_options,
+                )
+            },
+            Command::Report { report } => {
+                run_report(report, &address)
+            },
+            Command::Status { target, json } => {
+                match send_client_command(
+                    &address,
+                    ClientCommand::TargetAndStatusQuery(TargetAndStatusQuery { target }),
+                )? {
+                    ServerResponse::StatusResult(results) => display_status_results(&results, json),
+                    _ => anyhow::bail!("Unexpected server response"),
+                }
+            },
+            Command::Alerts { target, since, until, json } => {
+                let range = TimeRange::relative_to_now(
+                    since.map(|seconds| Duration::from_secs(seconds.into())),
+                    until.map(|seconds| Duration::from_secs(seconds.into())),
+                );
+
+                match send_client_command(
+                    &address,
+                    ClientCommand::TargetAndAlertQuery(TargetAndAlertQuery { target, range }),
+                )? {
+                    ServerResponse::AlertResult(report) => display_alert_report(&report, json),
+                    _ => anyhow::bail!("Unexpected server response"),
+                }
+            },
+            Command::Diagnostics { json } => {
+                match send_client_command(&address, ClientCommand::Diagnostics)? {
+                    ServerResponse::DiagnosticsResult(diagnostics) => {
+                        display_diagnostics(&diagnostics, json)
+                    }
+                    _ => anyhow::bail!("Unexpected server response"),
+                }
+            },
+            Command::Target { target } => {
+                run_target(target, &address)
             },
         }
     }
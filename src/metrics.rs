@@ -0,0 +1,230 @@
+use std::fmt::Write;
+use std::time::SystemTime;
+
+use crate::ping::PingReading;
+use crate::server::ServerState;
+
+/* Upper bounds of the latency histogram buckets, in seconds */
+pub const LATENCY_BUCKET_BOUNDARIES: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.15, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/* Running totals for a target, updated as readings are added to its history
+ * rather than computed from the history, so they keep counting after
+ * readings are evicted and are cheap to export.
+ */
+#[derive(Debug, Clone)]
+pub struct TargetMetrics {
+    pub latency_bucket_counts: Vec<u64>,
+    pub latency_sum_seconds: f64,
+    pub latency_count: u64,
+    pub last_rtt_seconds: Option<f64>,
+    pub last_reading_time: Option<SystemTime>,
+    pub packets_lost: u64,
+    pub probe_restarts: u64,
+    pub parse_failures: u64,
+    previous_sequence: Option<u64>,
+}
+
+impl Default for TargetMetrics {
+    fn default() -> Self {
+        TargetMetrics {
+            latency_bucket_counts: vec![0; LATENCY_BUCKET_BOUNDARIES.len()],
+            latency_sum_seconds: 0.0,
+            latency_count: 0,
+            last_rtt_seconds: None,
+            last_reading_time: None,
+            packets_lost: 0,
+            probe_restarts: 0,
+            parse_failures: 0,
+            previous_sequence: None,
+        }
+    }
+}
+
+impl TargetMetrics {
+    pub fn record_reading(&mut self, reading: &PingReading) {
+        let latency = reading.latency.as_secs_f64();
+
+        for (count, boundary) in self
+            .latency_bucket_counts
+            .iter_mut()
+            .zip(LATENCY_BUCKET_BOUNDARIES)
+        {
+            if latency <= *boundary {
+                *count += 1;
+            }
+        }
+
+        self.latency_sum_seconds += latency;
+        self.latency_count += 1;
+        self.last_rtt_seconds = Some(latency);
+        self.last_reading_time = Some(reading.timestamp);
+
+        /* Sequence numbers start over when the probe restarts, which is not
+         * a loss.
+         */
+        if let (Some(previous), Some(current)) = (self.previous_sequence, reading.sequence) {
+            if current > previous {
+                self.packets_lost += current - previous - 1;
+            }
+        }
+        self.previous_sequence = reading.sequence;
+    }
+
+    pub fn record_probe_restart(&mut self) {
+        self.probe_restarts += 1;
+        self.previous_sequence = None;
+    }
+
+    pub fn record_parse_failure(&mut self) {
+        self.parse_failures += 1;
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_metric_header(output: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} {metric_type}");
+}
+
+fn write_counter<F: Fn(&TargetMetrics) -> u64>(
+    output: &mut String,
+    targets: &[(String, TargetMetrics)],
+    name: &str,
+    help: &str,
+    value: F,
+) {
+    write_metric_header(output, name, "counter", help);
+    for (target, metrics) in targets {
+        let _ = writeln!(output, "{name}{{target=\"{target}\"}} {}", value(metrics));
+    }
+}
+
+/* Renders every target's metrics in the Prometheus text exposition format */
+pub fn render_metrics(server_state: &ServerState) -> String {
+    let mut targets: Vec<(String, TargetMetrics)> = server_state
+        .monitors
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(target, monitored_target)| {
+            (
+                escape_label_value(target),
+                monitored_target
+                    .reading_history
+                    .lock()
+                    .unwrap()
+                    .metrics()
+                    .clone(),
+            )
+        })
+        .collect();
+    targets.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut output = String::new();
+
+    write_metric_header(
+        &mut output,
+        "oxidenet_latency_seconds",
+        "histogram",
+        "Round trip time of ping replies.",
+    );
+    for (target, metrics) in &targets {
+        for (boundary, count) in LATENCY_BUCKET_BOUNDARIES
+            .iter()
+            .zip(&metrics.latency_bucket_counts)
+        {
+            let _ = writeln!(
+                output,
+                "oxidenet_latency_seconds_bucket{{target=\"{target}\",le=\"{boundary}\"}} {count}"
+            );
+        }
+        let _ = writeln!(
+            output,
+            "oxidenet_latency_seconds_bucket{{target=\"{target}\",le=\"+Inf\"}} {}",
+            metrics.latency_count
+        );
+        let _ = writeln!(
+            output,
+            "oxidenet_latency_seconds_sum{{target=\"{target}\"}} {}",
+            metrics.latency_sum_seconds
+        );
+        let _ = writeln!(
+            output,
+            "oxidenet_latency_seconds_count{{target=\"{target}\"}} {}",
+            metrics.latency_count
+        );
+    }
+
+    write_metric_header(
+        &mut output,
+        "oxidenet_last_rtt_seconds",
+        "gauge",
+        "Round trip time of the most recent ping reply.",
+    );
+    for (target, metrics) in &targets {
+        if let Some(last_rtt_seconds) = metrics.last_rtt_seconds {
+            let _ = writeln!(
+                output,
+                "oxidenet_last_rtt_seconds{{target=\"{target}\"}} {last_rtt_seconds}"
+            );
+        }
+    }
+
+    write_metric_header(
+        &mut output,
+        "oxidenet_last_reading_timestamp_seconds",
+        "gauge",
+        "Unix time of the most recent ping reply.",
+    );
+    for (target, metrics) in &targets {
+        if let Some(last_reading_time) = metrics.last_reading_time {
+            let timestamp = last_reading_time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            let _ = writeln!(
+                output,
+                "oxidenet_last_reading_timestamp_seconds{{target=\"{target}\"}} {timestamp}"
+            );
+        }
+    }
+
+    write_counter(
+        &mut output,
+        &targets,
+        "oxidenet_packets_received_total",
+        "Ping replies received.",
+        |metrics| metrics.latency_count,
+    );
+    write_counter(
+        &mut output,
+        &targets,
+        "oxidenet_packets_lost_total",
+        "Ping requests without a reply, detected from gaps in sequence numbers.",
+        |metrics| metrics.packets_lost,
+    );
+    write_counter(
+        &mut output,
+        &targets,
+        "oxidenet_probe_restarts_total",
+        "Times the ping process stopped and was restarted.",
+        |metrics| metrics.probe_restarts,
+    );
+    write_counter(
+        &mut output,
+        &targets,
+        "oxidenet_parse_failures_total",
+        "Ping reply lines that could not be parsed.",
+        |metrics| metrics.parse_failures,
+    );
+
+    output
+}
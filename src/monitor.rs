@@ -1 +1,367 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
+use smol::Task;
+
+use crate::config::PingMonitorConfig;
+use crate::health::{recent_window, HealthInputs, StateChange, TargetState, TargetStatus};
+use crate::ping::{PingMonitor, PingReading, PingReadingHistory};
+use crate::server::ServerError;
+use crate::sla::SlaConfig;
+
+/* Host names are at most 253 characters, anything longer is not a target */
+const MAX_TARGET_LENGTH: usize = 253;
+
+/* Targets are passed to ping as an argument, so they must not be mistaken for
+ * an option.
+ */
+fn validate_target(target: &str) -> Result<(), ServerError> {
+    if target.is_empty() || target.len() > MAX_TARGET_LENGTH {
+        return Err(ServerError::InvalidTarget(format!(
+            "target must be 1 to {MAX_TARGET_LENGTH} characters long"
+        )));
+    }
+
+    if target.starts_with('-') || target.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(ServerError::InvalidTarget(format!(
+            "{target:?} is not a host name or address"
+        )));
+    }
+
+    Ok(())
+}
+
+fn validate_monitor(target: &str, config: &PingMonitorConfig) -> Result<Duration, ServerError> {
+    validate_target(target)?;
+    config
+        .anomaly
+        .validate()
+        .and_then(|()| config.sla.as_ref().map_or(Ok(()), SlaConfig::validate))
+        .and_then(|()| config.history_length())
+        .map_err(|e| ServerError::InvalidTarget(format!("{target}: {e}")))
+}
+
+/* The reading history outlives the monitor task, so pausing and resuming a
+ * target keeps its readings and subscribers. Targets added at runtime are not
+ * `from_config`, so reloading the config leaves them alone.
+ */
+#[derive(Debug)]
+pub struct MonitoredTarget {
+    pub config: PingMonitorConfig,
+    pub reading_history: Arc<Mutex<PingReadingHistory>>,
+    task: Option<Task<()>>,
+    state: TargetState,
+    state_since: SystemTime,
+    active_since: SystemTime,
+    from_config: bool,
+}
+
+impl MonitoredTarget {
+    pub fn is_paused(&self) -> bool {
+        self.task.is_none()
+    }
+
+    /* Re-evaluates the state of the target, recording when it changed */
+    fn check_health(
+        &mut self,
+        target: &str,
+        now: SystemTime,
+        state_changes: &mut Vec<StateChange>,
+    ) -> TargetStatus {
+        let history = self.reading_history.lock().unwrap();
+        let window_start = now
+            .checked_sub(recent_window(&self.config))
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let recent_readings = history.readings_since(window_start);
+        let probe_running = history.is_probe_running();
+        let metrics = history.metrics().clone();
+        drop(history);
+
+        let recent_readings: Vec<&PingReading> = recent_readings.iter().map(Arc::as_ref).collect();
+        let inputs = HealthInputs {
+            config: &self.config,
+            paused: self.is_paused(),
+            probe_running,
+            active_since: self.active_since,
+            recent_readings: &recent_readings,
+        };
+
+        let state = inputs.state(now);
+        if state != self.state {
+            log::info!("{target} changed from {} to {state}", self.state);
+            state_changes.push(StateChange {
+                target: target.to_string(),
+                from: self.state,
+                to: state,
+                time: now,
+            });
+            self.state = state;
+            self.state_since = now;
+        }
+
+        TargetStatus {
+            state,
+            state_since: self.state_since,
+            last_reading_time: metrics.last_reading_time,
+            last_latency: metrics.last_rtt_seconds.map(Duration::from_secs_f64),
+            recent_loss_percent: inputs.recent_loss_percent(),
+        }
+    }
+}
+
+/* A target picked by a query, taken out of the monitors so that the query
+ * does not hold their lock. `active_since` is `None` while it is paused.
+ */
+pub struct SelectedTarget {
+    pub target: String,
+    pub config: PingMonitorConfig,
+    pub reading_history: Arc<Mutex<PingReadingHistory>>,
+    pub active_since: Option<SystemTime>,
+}
+
+/* Dropping a monitor task cancels it, which kills its ping process */
+fn spawn_monitor(
+    target: &str,
+    config: &PingMonitorConfig,
+    reading_history: Arc<Mutex<PingReadingHistory>>,
+) -> Task<()> {
+    let mut ping_monitor =
+        PingMonitor::new(target.to_string(), config.interval_seconds, reading_history);
+
+    smol::spawn(async move {
+        if let Err(e) = ping_monitor.watch().await {
+            log::error!(
+                "Ping monitor task for {} stopped unexpectedly with error: {e}",
+                ping_monitor.target()
+            );
+        }
+    })
+}
+
+/* Every target the service monitors, which can change while it runs. State
+ * changes are kept until the next health update, since a status query can
+ * be the one to notice them.
+ */
+#[derive(Debug, Default)]
+pub struct Monitors {
+    targets: HashMap<String, MonitoredTarget>,
+    state_changes: Vec<StateChange>,
+}
+
+impl Monitors {
+    pub fn add(&mut self, target: String, config: PingMonitorConfig) -> Result<(), ServerError> {
+        let history_length = validate_monitor(&target, &config)?;
+
+        if self.targets.contains_key(&target) {
+            return Err(ServerError::InvalidTarget(format!(
+                "{target} is already monitored"
+            )));
+        }
+
+        let reading_history = Arc::new(Mutex::new(PingReadingHistory::new(
+            config.interval_seconds,
+            history_length,
+            config.anomaly,
+        )));
+        let task = spawn_monitor(&target, &config, reading_history.clone());
+        let now = SystemTime::now();
+
+        log::info!("Started monitoring {target}");
+        self.targets.insert(
+            target,
+            MonitoredTarget {
+                config,
+                reading_history,
+                task: Some(task),
+                state: TargetState::Up,
+                state_since: now,
+                active_since: now,
+                from_config: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, target: &str) -> Result<(), ServerError> {
+        self.targets
+            .remove(target)
+            .ok_or_else(|| ServerError::UnknownTarget(target.to_string()))?;
+
+        log::info!("Stopped monitoring {target}");
+        Ok(())
+    }
+
+    /* The history is kept and resized, the monitor is only restarted when
+     * the interval changed.
+     */
+    pub fn reconfigure(
+        &mut self,
+        target: &str,
+        config: PingMonitorConfig,
+    ) -> Result<(), ServerError> {
+        let history_length = validate_monitor(target, &config)?;
+        let monitored_target = self.get_mut(target)?;
+
+        {
+            let mut history = monitored_target.reading_history.lock().unwrap();
+            history.resize(config.interval_seconds, history_length);
+            history.set_anomaly_config(config.anomaly);
+        }
+
+        let interval_changed = monitored_target.config.interval_seconds != config.interval_seconds;
+        monitored_target.config = config;
+
+        if interval_changed && monitored_target.task.is_some() {
+            monitored_target.task = Some(spawn_monitor(
+                target,
+                &config,
+                monitored_target.reading_history.clone(),
+            ));
+            monitored_target.active_since = SystemTime::now();
+        }
+
+        log::info!("Reconfigured monitoring {target}");
+        Ok(())
+    }
+
+    /* Makes the targets from the config match `configs`, leaving targets
+     * whose config is unchanged alone, including whether they are paused.
+     * Targets added at runtime are kept unless `configs` has them, in which
+     * case the config takes them over. Nothing is changed unless every
+     * config is valid.
+     */
+    pub fn apply(
+        &mut self,
+        configs: &HashMap<String, PingMonitorConfig>,
+    ) -> Result<(), ServerError> {
+        for (target, config) in configs {
+            validate_monitor(target, config)?;
+        }
+
+        let removed: Vec<String> = self
+            .targets
+            .iter()
+            .filter(|(target, monitored_target)| {
+                monitored_target.from_config && !configs.contains_key(*target)
+            })
+            .map(|(target, _)| target.clone())
+            .collect();
+        for target in removed {
+            self.remove(&target)?;
+        }
+
+        for (target, monitored_target) in &self.targets {
+            if !monitored_target.from_config && !configs.contains_key(target) {
+                log::info!("Keeping {target}, which was added at runtime and is not in the config");
+            }
+        }
+
+        for (target, config) in configs {
+            match self.targets.get(target) {
+                None => self.add(target.clone(), *config)?,
+                Some(monitored_target) if monitored_target.config != *config => {
+                    self.reconfigure(target, *config)?
+                }
+                Some(_) => {}
+            }
+            self.get_mut(target)?.from_config = true;
+        }
+
+        Ok(())
+    }
+
+    /* Pausing a paused target, or resuming a running one, does nothing */
+    pub fn pause(&mut self, target: &str) -> Result<(), ServerError> {
+        let monitored_target = self.get_mut(target)?;
+
+        if monitored_target.task.take().is_some() {
+            monitored_target
+                .reading_history
+                .lock()
+                .unwrap()
+                .set_probe_stopped();
+            log::info!("Paused monitoring {target}");
+        }
+
+        Ok(())
+    }
+
+    pub fn resume(&mut self, target: &str) -> Result<(), ServerError> {
+        let monitored_target = self.get_mut(target)?;
+
+        if monitored_target.task.is_none() {
+            monitored_target.task = Some(spawn_monitor(
+                target,
+                &monitored_target.config,
+                monitored_target.reading_history.clone(),
+            ));
+            monitored_target.active_since = SystemTime::now();
+            log::info!("Resumed monitoring {target}");
+        }
+
+        Ok(())
+    }
+
+    fn get_mut(&mut self, target: &str) -> Result<&mut MonitoredTarget, ServerError> {
+        self.targets
+            .get_mut(target)
+            .ok_or_else(|| ServerError::UnknownTarget(target.to_string()))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &MonitoredTarget)> {
+        self.targets.iter()
+    }
+
+    pub fn update_health(&mut self, now: SystemTime) -> Vec<StateChange> {
+        for (target, monitored_target) in &mut self.targets {
+            monitored_target.check_health(target, now, &mut self.state_changes);
+        }
+
+        std::mem::take(&mut self.state_changes)
+    }
+
+    pub fn status(
+        &mut self,
+        target: &Option<String>,
+        now: SystemTime,
+    ) -> Result<HashMap<String, TargetStatus>, ServerError> {
+        if let Some(target) = target {
+            let monitored_target = self
+                .targets
+                .get_mut(target)
+                .ok_or_else(|| ServerError::UnknownTarget(target.to_string()))?;
+            let status = monitored_target.check_health(target, now, &mut self.state_changes);
+            Ok(HashMap::from([(target.clone(), status)]))
+        } else {
+            Ok(self
+                .targets
+                .iter_mut()
+                .map(|(target, monitored_target)| {
+                    let status =
+                        monitored_target.check_health(target, now, &mut self.state_changes);
+                    (target.clone(), status)
+                })
+                .collect())
+        }
+    }
+
+    pub fn select(&self, target: &Option<String>) -> Result<Vec<SelectedTarget>, ServerError> {
+        let select = |(target, monitored_target): (&String, &MonitoredTarget)| SelectedTarget {
+            target: target.clone(),
+            config: monitored_target.config,
+            reading_history: monitored_target.reading_history.clone(),
+            active_since: (!monitored_target.is_paused()).then_some(monitored_target.active_since),
+        };
+
+        if let Some(target) = target {
+            self.targets
+                .get_key_value(target)
+                .map(|target_and_monitor| vec![select(target_and_monitor)])
+                .ok_or_else(|| ServerError::UnknownTarget(target.clone()))
+        } else {
+            Ok(self.targets.iter().map(select).collect())
+        }
+    }
+}
@@ -0,0 +1,929 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_rustls::pki_types::ServerName;
+use futures_rustls::rustls::ClientConfig;
+use futures_rustls::TlsConnector;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use smol::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use smol::net::TcpStream;
+use smol::Timer;
+
+use crate::alerts::{selector_matches, AlertEvent, AlertTransition};
+use crate::health::{StateChange, TargetState};
+use crate::remote::load_root_store;
+use crate::server::ServerState;
+use crate::util::{read_state_file, with_timeout, write_state_file};
+
+/* Targets that stop producing readings are notified about as if this were an
+ * alert rule, so webhooks can route them like any other rule.
+ */
+pub const MONITOR_DOWN_RULE: &str = "monitor-down";
+
+/* Used to verify https webhooks that do not configure a CA certificate */
+const DEFAULT_CA_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+const NOTIFIER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RESPONSE_HEADER_SIZE: usize = 64 * 1024;
+
+/* A webhook that is down for long enough would otherwise grow the outbox
+ * without bound, the oldest notifications are dropped past this.
+ */
+const MAX_OUTBOX_ENTRIES: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NotificationsConfig {
+    #[serde(rename = "outbox-path")]
+    pub outbox_path: Option<PathBuf>,
+    #[serde(rename = "max-attempts")]
+    pub max_attempts: u32,
+    #[serde(rename = "initial-backoff-seconds")]
+    pub initial_backoff_seconds: f32,
+    #[serde(rename = "max-backoff-seconds")]
+    pub max_backoff_seconds: f32,
+    #[serde(rename = "timeout-seconds")]
+    pub timeout_seconds: f32,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        NotificationsConfig {
+            outbox_path: None,
+            max_attempts: 10,
+            initial_backoff_seconds: 5.0,
+            max_backoff_seconds: 600.0,
+            timeout_seconds: 10.0,
+        }
+    }
+}
+
+impl NotificationsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err(String::from("max-attempts must be at least 1"));
+        }
+
+        for (name, seconds) in [
+            ("initial-backoff-seconds", self.initial_backoff_seconds),
+            ("max-backoff-seconds", self.max_backoff_seconds),
+            ("timeout-seconds", self.timeout_seconds),
+        ] {
+            if Duration::try_from_secs_f32(seconds).is_err() {
+                return Err(format!(
+                    "{name} must be a non-negative number of seconds, not {seconds}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /* Doubles with every failed attempt, up to the maximum */
+    fn backoff(&self, attempts: u32) -> Duration {
+        let initial = Duration::from_secs_f32(self.initial_backoff_seconds);
+        let max = Duration::from_secs_f32(self.max_backoff_seconds);
+
+        initial
+            .checked_mul(2_u32.saturating_pow(attempts.saturating_sub(1)))
+            .map_or(max, |backoff| backoff.min(max))
+    }
+}
+
+/* Notifications of the rules matched by `rules` are sent to `url`, where `*`
+ * matches any characters and an empty list matches every rule. Templates
+ * have `{{name}}` replaced by the value of a field of the notification,
+ * escaped to be placed inside a JSON string.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub rules: Vec<String>,
+    pub template: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(rename = "ca-certificate")]
+    pub ca_certificate: Option<PathBuf>,
+}
+
+struct WebhookUrl {
+    tls: bool,
+    authority: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+fn parse_url(url: &str) -> Result<WebhookUrl, String> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else {
+        return Err(format!("{url} is not an http or https URL"));
+    };
+
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (
+            host,
+            port.parse()
+                .map_err(|_| format!("{url} does not have a valid port"))?,
+        ),
+        _ => (authority, if tls { 443 } else { 80 }),
+    };
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(format!("{url} does not have a host"));
+    }
+
+    Ok(WebhookUrl {
+        tls,
+        authority: authority.to_string(),
+        host: host.to_string(),
+        port,
+        path: if path.is_empty() { "/" } else { path }.to_string(),
+    })
+}
+
+impl WebhookConfig {
+    fn validate(&self) -> Result<(), String> {
+        parse_url(&self.url)?;
+
+        if self.rules.iter().any(String::is_empty) {
+            return Err(String::from("rule selectors must not be empty"));
+        }
+
+        if self.headers.iter().any(|(name, value)| {
+            name.is_empty() || name.contains([':', '\r', '\n']) || value.contains(['\r', '\n'])
+        }) {
+            return Err(String::from("headers must be single line names and values"));
+        }
+
+        for notification in Notification::samples() {
+            if let Err(e) = serde_json::from_str::<serde_json::Value>(&self.render(&notification)) {
+                return Err(format!(
+                    "template is not JSON for {} notifications: {e}",
+                    notification.rule
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn routes(&self, rule: &str) -> bool {
+        self.rules.is_empty()
+            || self
+                .rules
+                .iter()
+                .any(|selector| selector_matches(selector, rule))
+    }
+
+    fn render(&self, notification: &Notification) -> String {
+        let Some(template) = &self.template else {
+            return notification.to_json().to_string();
+        };
+
+        /* A single pass, so that values holding placeholders are not
+         * substituted again. Unknown placeholders are left as they are.
+         */
+        let fields: HashMap<&str, String> = notification.fields().into_iter().collect();
+        let mut body = String::with_capacity(template.len());
+        let mut rest = template.as_str();
+
+        while let Some(start) = rest.find("{{") {
+            body.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let value = rest
+                .find("}}")
+                .and_then(|end| Some((end, fields.get(&rest[2..end])?)));
+            let Some((end, value)) = value else {
+                body.push_str("{{");
+                rest = &rest[2..];
+                continue;
+            };
+
+            /* Escaped as JSON, without the quotes around it */
+            let escaped = serde_json::to_string(value).unwrap_or_default();
+            body.push_str(&escaped[1..escaped.len() - 1]);
+            rest = &rest[end + 2..];
+        }
+
+        body.push_str(rest);
+        body
+    }
+}
+
+pub fn validate_notifications(
+    config: &NotificationsConfig,
+    webhooks: &HashMap<String, WebhookConfig>,
+) -> Result<(), String> {
+    config
+        .validate()
+        .map_err(|e| format!("notifications: {e}"))?;
+
+    for (name, webhook) in webhooks {
+        webhook
+            .validate()
+            .map_err(|e| format!("webhook {name}: {e}"))?;
+    }
+
+    Ok(())
+}
+
+/* Alert fields are empty for monitor-down notifications, and the state is
+ * empty for alerts.
+ */
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub rule: String,
+    pub target: String,
+    pub transition: AlertTransition,
+    pub time: SystemTime,
+    pub message: String,
+    pub metric: Option<String>,
+    pub unit: Option<String>,
+    pub value: Option<f64>,
+    pub threshold: Option<f64>,
+    pub state: Option<TargetState>,
+}
+
+impl Notification {
+    pub fn from_alert(event: &AlertEvent) -> Notification {
+        let unit = event.metric.unit();
+
+        Notification {
+            rule: event.rule.clone(),
+            target: event.target.clone(),
+            transition: event.transition,
+            time: event.time,
+            message: format!(
+                "{} {} for {}, {} is {:.1} {unit}, threshold {:.1} {unit}",
+                event.rule,
+                event.transition,
+                event.target,
+                event.metric,
+                event.value,
+                event.threshold
+            ),
+            metric: Some(event.metric.to_string()),
+            unit: Some(unit.to_string()),
+            value: Some(event.value),
+            threshold: Some(event.threshold),
+            state: None,
+        }
+    }
+
+    /* Only changes into and out of the states without readings notify */
+    pub fn from_state_change(change: &StateChange) -> Option<Notification> {
+        let transition = match (change.from.is_down(), change.to.is_down()) {
+            (false, true) => AlertTransition::Fired,
+            (true, false) => AlertTransition::Resolved,
+            _ => return None,
+        };
+
+        Some(Notification {
+            rule: MONITOR_DOWN_RULE.to_string(),
+            target: change.target.clone(),
+            transition,
+            time: change.time,
+            message: format!(
+                "{} changed from {} to {}",
+                change.target, change.from, change.to
+            ),
+            metric: None,
+            unit: None,
+            value: None,
+            threshold: None,
+            state: Some(change.to),
+        })
+    }
+
+    /* One of each kind, to check templates against */
+    fn samples() -> [Notification; 2] {
+        let time = SystemTime::UNIX_EPOCH;
+
+        [
+            Notification {
+                rule: String::from("alert"),
+                target: String::from("example.com"),
+                transition: AlertTransition::Fired,
+                time,
+                message: String::new(),
+                metric: Some(String::from("latency")),
+                unit: Some(String::from("ms")),
+                value: Some(1.0),
+                threshold: Some(1.0),
+                state: None,
+            },
+            Notification {
+                rule: MONITOR_DOWN_RULE.to_string(),
+                target: String::from("example.com"),
+                transition: AlertTransition::Fired,
+                time,
+                message: String::new(),
+                metric: None,
+                unit: None,
+                value: None,
+                threshold: None,
+                state: Some(TargetState::Down),
+            },
+        ]
+    }
+
+    fn formatted_time(&self) -> String {
+        DateTime::<Utc>::from(self.time).to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+
+        vec![
+            ("rule", self.rule.clone()),
+            ("target", self.target.clone()),
+            ("transition", self.transition.to_string()),
+            ("time", self.formatted_time()),
+            ("message", self.message.clone()),
+            ("metric", optional(self.metric.clone())),
+            ("unit", optional(self.unit.clone())),
+            (
+                "value",
+                optional(self.value.map(|value| format!("{value:.1}"))),
+            ),
+            (
+                "threshold",
+                optional(self.threshold.map(|threshold| format!("{threshold:.1}"))),
+            ),
+            ("state", optional(self.state.map(|state| state.to_string()))),
+        ]
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "rule": self.rule,
+            "target": self.target,
+            "transition": self.transition,
+            "time": self.formatted_time(),
+            "message": self.message,
+            "metric": self.metric,
+            "unit": self.unit,
+            "value": self.value,
+            "threshold": self.threshold,
+            "state": self.state,
+        })
+    }
+}
+
+/* Bodies are rendered when a notification is queued, so a retry sends the
+ * same payload even if the template changed in between.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct OutboxEntry {
+    id: u64,
+    webhook: String,
+    body: String,
+    created: SystemTime,
+    attempts: u32,
+    next_attempt: SystemTime,
+}
+
+/* The outbox is written to disk after it changes, so notifications that
+ * were not delivered yet are retried after a restart. Writing is left to the
+ * delivery task, so the notifier is not locked while the disk is slow.
+ */
+#[derive(Debug)]
+pub struct Notifier {
+    config: NotificationsConfig,
+    webhooks: HashMap<String, WebhookConfig>,
+    outbox_path: PathBuf,
+    outbox: Vec<OutboxEntry>,
+    next_id: u64,
+    in_flight: HashSet<u64>,
+    unsaved: bool,
+}
+
+fn load_outbox(path: &Path) -> anyhow::Result<Vec<OutboxEntry>> {
+    read_state_file(path, "notification outbox")
+}
+
+impl Notifier {
+    /* Nothing is read or written unless webhooks are configured */
+    pub fn new(
+        config: NotificationsConfig,
+        webhooks: HashMap<String, WebhookConfig>,
+        outbox_path: PathBuf,
+    ) -> anyhow::Result<Notifier> {
+        let mut outbox = if webhooks.is_empty() {
+            vec![]
+        } else {
+            load_outbox(&outbox_path)?
+        };
+
+        outbox.retain(|entry| {
+            let configured = webhooks.contains_key(&entry.webhook);
+            if !configured {
+                log::warn!(
+                    "Dropping a notification for webhook {}, it is no longer configured",
+                    entry.webhook
+                );
+            }
+            configured
+        });
+
+        if !outbox.is_empty() {
+            log::info!(
+                "Resuming delivery of {} notification(s) from {}",
+                outbox.len(),
+                outbox_path.display()
+            );
+        }
+
+        Ok(Notifier {
+            config,
+            webhooks,
+            outbox_path,
+            next_id: outbox.iter().map(|entry| entry.id + 1).max().unwrap_or(0),
+            outbox,
+            in_flight: HashSet::new(),
+            unsaved: false,
+        })
+    }
+
+    /* The outbox as it should be written, if it changed since it last was */
+    fn take_unsaved(&mut self) -> Option<(PathBuf, Vec<u8>)> {
+        if !std::mem::take(&mut self.unsaved) {
+            return None;
+        }
+
+        match serde_json::to_vec(&self.outbox) {
+            Ok(contents) => Some((self.outbox_path.clone(), contents)),
+            Err(e) => {
+                log::error!("Could not encode the notification outbox: {e}");
+                None
+            }
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.outbox.len()
+    }
+
+    pub fn notify(&mut self, notification: &Notification) {
+        let now = SystemTime::now();
+        let mut queued = false;
+
+        for (name, webhook) in &self.webhooks {
+            if !webhook.routes(&notification.rule) {
+                continue;
+            }
+
+            self.outbox.push(OutboxEntry {
+                id: self.next_id,
+                webhook: name.clone(),
+                body: webhook.render(notification),
+                created: now,
+                attempts: 0,
+                next_attempt: now,
+            });
+            self.next_id += 1;
+            queued = true;
+        }
+
+        if !queued {
+            return;
+        }
+
+        while self.outbox.len() > MAX_OUTBOX_ENTRIES {
+            let dropped = self.outbox.remove(0);
+            log::error!(
+                "Dropping a notification for webhook {}, the outbox is full",
+                dropped.webhook
+            );
+        }
+
+        self.unsaved = true;
+    }
+
+    /* Webhooks get their notifications in order, so only the oldest one of
+     * each webhook is sent at a time. Those that are due are marked as being
+     * delivered, so they are not picked up again while their delivery runs.
+     */
+    fn take_due(&mut self, now: SystemTime) -> Vec<(OutboxEntry, WebhookConfig)> {
+        let mut oldest: HashSet<&String> = HashSet::new();
+
+        let due: Vec<(OutboxEntry, WebhookConfig)> = self
+            .outbox
+            .iter()
+            .filter(|entry| oldest.insert(&entry.webhook))
+            .filter(|entry| entry.next_attempt <= now && !self.in_flight.contains(&entry.id))
+            .filter_map(|entry| {
+                let webhook = self.webhooks.get(&entry.webhook)?;
+                Some((entry.clone(), webhook.clone()))
+            })
+            .collect();
+
+        self.in_flight.extend(due.iter().map(|(entry, _)| entry.id));
+
+        due
+    }
+
+    fn finish(&mut self, id: u64, result: anyhow::Result<()>) {
+        self.in_flight.remove(&id);
+
+        let Some(index) = self.outbox.iter().position(|entry| entry.id == id) else {
+            return;
+        };
+
+        match result {
+            Ok(()) => {
+                let entry = self.outbox.remove(index);
+                log::info!("Delivered a notification to webhook {}", entry.webhook);
+            }
+            Err(e) => {
+                let entry = &mut self.outbox[index];
+                entry.attempts += 1;
+
+                if entry.attempts >= self.config.max_attempts {
+                    log::error!(
+                        "Giving up on a notification for webhook {} after {} attempt(s): {e}",
+                        entry.webhook,
+                        entry.attempts
+                    );
+                    self.outbox.remove(index);
+                } else {
+                    let backoff = self.config.backoff(entry.attempts);
+                    log::warn!(
+                        "Could not deliver a notification to webhook {}, retrying in {backoff:?}: {e}",
+                        entry.webhook
+                    );
+                    entry.next_attempt = SystemTime::now() + backoff;
+                }
+            }
+        }
+
+        self.unsaved = true;
+    }
+}
+
+fn tls_client_config(webhook: &WebhookConfig) -> anyhow::Result<ClientConfig> {
+    let ca_certificate = webhook
+        .ca_certificate
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CA_BUNDLE));
+
+    Ok(ClientConfig::builder()
+        .with_root_certificates(load_root_store(&ca_certificate)?)
+        .with_no_client_auth())
+}
+
+/* Sends the request and returns the status code of the response, the rest
+ * of the response is not needed.
+ */
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &[u8],
+) -> anyhow::Result<u16> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut response = httparse::Response::new(&mut headers);
+
+        if let httparse::Status::Complete(_) = response.parse(&buf)? {
+            return response
+                .code
+                .ok_or_else(|| anyhow::anyhow!("Response without a status code"));
+        }
+
+        if buf.len() > MAX_RESPONSE_HEADER_SIZE {
+            anyhow::bail!("Response headers are larger than {MAX_RESPONSE_HEADER_SIZE} bytes");
+        }
+
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            anyhow::bail!("Connection closed before the response was complete");
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+}
+
+async fn post(webhook: &WebhookConfig, body: &str) -> anyhow::Result<()> {
+    let url = parse_url(&webhook.url).map_err(anyhow::Error::msg)?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: oxidenet/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.authority,
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    );
+    for (name, value) in &webhook.headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    let stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+
+    let status = if url.tls {
+        let connector = TlsConnector::from(Arc::new(tls_client_config(webhook)?));
+        let stream = connector
+            .connect(ServerName::try_from(url.host.clone())?, stream)
+            .await?;
+        exchange(stream, request.as_bytes()).await?
+    } else {
+        exchange(stream, request.as_bytes()).await?
+    };
+
+    if !(200..300).contains(&status) {
+        anyhow::bail!("{} responded with status {status}", webhook.url);
+    }
+
+    Ok(())
+}
+
+/* Each delivery runs in its own task, so a slow webhook does not hold up the
+ * others.
+ */
+pub async fn deliver_notifications(server_state: Arc<ServerState>) {
+    loop {
+        Timer::after(NOTIFIER_POLL_INTERVAL).await;
+
+        let (due, timeout, unsaved) = {
+            let mut notifier = server_state.notifier.lock().unwrap();
+            (
+                notifier.take_due(SystemTime::now()),
+                Duration::from_secs_f32(notifier.config.timeout_seconds),
+                notifier.take_unsaved(),
+            )
+        };
+
+        if let Some((outbox_path, contents)) = unsaved {
+            let saved = {
+                let outbox_path = outbox_path.clone();
+                smol::unblock(move || write_state_file(&outbox_path, &contents)).await
+            };
+            if let Err(e) = saved {
+                log::error!(
+                    "Could not save notification outbox {}: {e}",
+                    outbox_path.display()
+                );
+            }
+        }
+
+        for (entry, webhook) in due {
+            let server_state = server_state.clone();
+
+            smol::spawn(async move {
+                let result = with_timeout(timeout, "the webhook to respond", async {
+                    post(&webhook, &entry.body).await
+                })
+                .await;
+
+                server_state
+                    .notifier
+                    .lock()
+                    .unwrap()
+                    .finish(entry.id, result);
+            })
+            .detach();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::alerts::AlertMetric;
+
+    /* Answers each request with the next status, and passes on what it was
+     * sent.
+     */
+    fn stand_in(statuses: Vec<u16>) -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut head = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                sender
+                    .send((head, String::from_utf8(body).unwrap()))
+                    .unwrap();
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Whatever\r\nContent-Length: 0\r\n\r\n"
+                )
+                .unwrap();
+            }
+        });
+
+        (url, receiver)
+    }
+
+    fn test_notifier(name: &str, url: &str) -> Notifier {
+        let outbox_path = std::env::temp_dir().join(format!(
+            "oxidenet-outbox-{name}-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&outbox_path);
+
+        let webhook = WebhookConfig {
+            url: url.to_string(),
+            rules: vec![String::from("slow"), String::from(MONITOR_DOWN_RULE)],
+            template: Some(String::from(r#"{"text": "{{message}}"}"#)),
+            headers: HashMap::from([(String::from("Authorization"), String::from("Bearer abc"))]),
+            ca_certificate: None,
+        };
+
+        Notifier::new(
+            NotificationsConfig {
+                initial_backoff_seconds: 60.0,
+                max_attempts: 3,
+                ..NotificationsConfig::default()
+            },
+            HashMap::from([(String::from("chat"), webhook)]),
+            outbox_path,
+        )
+        .unwrap()
+    }
+
+    fn notifications() -> [Notification; 2] {
+        let time = SystemTime::now();
+
+        [
+            Notification::from_alert(&AlertEvent {
+                rule: String::from("slow"),
+                target: String::from("example.com"),
+                metric: AlertMetric::Latency,
+                transition: AlertTransition::Fired,
+                time,
+                value: 150.0,
+                threshold: 100.0,
+            }),
+            Notification::from_state_change(&StateChange {
+                target: String::from("example.com"),
+                from: TargetState::Up,
+                to: TargetState::Down,
+                time,
+            })
+            .unwrap(),
+        ]
+    }
+
+    #[test]
+    fn values_are_substituted_once() {
+        let [alert, _] = notifications();
+        let alert = Notification {
+            target: String::from("{{rule}} \"quoted\""),
+            ..alert
+        };
+        let webhook = WebhookConfig {
+            template: Some(String::from(
+                r#"{"target": "{{target}}", "rule": "{{rule}}", "other": "{{other}} {{"}"#,
+            )),
+            ..test_notifier("render", "http://127.0.0.1/").webhooks["chat"].clone()
+        };
+
+        assert_eq!(
+            webhook.render(&alert),
+            r#"{"target": "{{rule}} \"quoted\"", "rule": "slow", "other": "{{other}} {{"}"#
+        );
+    }
+
+    fn save(notifier: &mut Notifier) {
+        if let Some((path, contents)) = notifier.take_unsaved() {
+            write_state_file(&path, &contents).unwrap();
+        }
+    }
+
+    fn deliver(notifier: &mut Notifier, now: SystemTime) -> Vec<bool> {
+        let due = notifier.take_due(now);
+
+        due.into_iter()
+            .map(|(entry, webhook)| {
+                let result = smol::block_on(post(&webhook, &entry.body));
+                let delivered = result.is_ok();
+                notifier.finish(entry.id, result);
+                delivered
+            })
+            .collect()
+    }
+
+    #[test]
+    fn failed_deliveries_are_retried_after_a_backoff() {
+        let (url, requests) = stand_in(vec![500, 200]);
+        let mut notifier = test_notifier("retry", &url);
+        let [alert, _] = notifications();
+
+        notifier.notify(&alert);
+        let now = SystemTime::now();
+        assert_eq!(deliver(&mut notifier, now), [false]);
+
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(head.contains("Authorization: Bearer abc\r\n"));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()["text"],
+            alert.message
+        );
+
+        /* Not retried until the backoff has passed */
+        assert!(deliver(&mut notifier, now).is_empty());
+        assert_eq!(notifier.pending(), 1);
+
+        let later = SystemTime::now() + Duration::from_secs(61);
+        assert_eq!(deliver(&mut notifier, later), [true]);
+        assert_eq!(requests.recv().unwrap().1, body);
+        assert_eq!(notifier.pending(), 0);
+
+        save(&mut notifier);
+        assert!(load_outbox(&notifier.outbox_path).unwrap().is_empty());
+        std::fs::remove_file(&notifier.outbox_path).unwrap();
+    }
+
+    #[test]
+    fn undelivered_notifications_are_resumed_in_order_from_the_outbox() {
+        /* Nothing listens here once the listener is dropped */
+        let url = format!(
+            "http://{}/hook",
+            TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        );
+        let mut notifier = test_notifier("resume", &url);
+        let [alert, down] = notifications();
+
+        notifier.notify(&alert);
+        notifier.notify(&down);
+        assert_eq!(deliver(&mut notifier, SystemTime::now()), [false]);
+        save(&mut notifier);
+
+        let (url, requests) = stand_in(vec![200, 200]);
+        let mut webhooks = notifier.webhooks.clone();
+        webhooks.get_mut("chat").unwrap().url = url;
+        let mut resumed = Notifier::new(
+            notifier.config.clone(),
+            webhooks,
+            notifier.outbox_path.clone(),
+        )
+        .unwrap();
+        assert_eq!(resumed.pending(), 2);
+
+        let later = SystemTime::now() + Duration::from_secs(61);
+        assert_eq!(deliver(&mut resumed, later), [true]);
+        assert_eq!(deliver(&mut resumed, later), [true]);
+
+        let messages: Vec<String> = requests
+            .iter()
+            .take(2)
+            .map(|(_, body)| {
+                serde_json::from_str::<serde_json::Value>(&body).unwrap()["text"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(messages, [alert.message, down.message]);
+
+        std::fs::remove_file(&resumed.outbox_path).unwrap();
+    }
+}
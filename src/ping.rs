@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::process::Stdio;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
@@ -6,7 +6,13 @@ use std::time::{Duration, SystemTime};
 use smol::process::Command;
 use smol::Timer;
 
+use crate::anomaly::{AnomalyConfig, AnomalyDetector, AnomalyEvent};
+use crate::changepoint::LevelShift;
 use crate::command_watcher::{watch, InputConsumptionResult};
+use crate::metrics::TargetMetrics;
+use crate::seasonal::SeasonalBaseline;
+use crate::stats::TimeRange;
+use crate::subscription::Subscriber;
 
 use serde::{Deserialize, Serialize};
 
@@ -30,79 +36,261 @@ impl PingReadingQuery {
         }
     }
 
-    pub fn query(&self, readings: &[PingReading]) -> Vec<PingReading> {
-        let mut reading_history: Vec<(bool, usize, &PingReading)> = vec![];
+    /* Walks the readings once, keeping a sliding window of the readings within
+     * `max_window` of the current one. Whenever the window holds more than
+     * `min_intensity` readings over the threshold, the whole window is added to
+     * the episode being built, which is closed once a later window no longer
+     * touches it.
+     */
+    pub fn query<'a, I: IntoIterator<Item = &'a PingReading>>(
+        &self,
+        readings: I,
+    ) -> Vec<PingEpisode> {
+        self.query_with_threshold(readings, |_| self.latency_higher_than)
+    }
+
+    /* Like `query`, with a threshold that can differ from reading to reading */
+    pub fn query_with_threshold<'a, I, F>(&self, readings: I, threshold: F) -> Vec<PingEpisode>
+    where
+        I: IntoIterator<Item = &'a PingReading>,
+        F: Fn(&PingReading) -> Duration,
+    {
+        let mut window: VecDeque<(usize, &PingReading)> = VecDeque::new();
         let mut intensity = 0;
-        let mut included_readings: HashSet<usize> = HashSet::new();
+        let mut evicted: VecDeque<&PingReading> = VecDeque::new();
+        let mut open_episode: Option<EpisodeBuilder> = None;
+        let mut closing_episodes: VecDeque<EpisodeBuilder> = VecDeque::new();
+        let mut closed_episodes: Vec<EpisodeBuilder> = vec![];
 
-        for (i, reading) in readings.iter().enumerate() {
-            if reading.latency > self.latency_higher_than {
-                reading_history.push((true, i, reading));
+        for (i, reading) in readings.into_iter().enumerate() {
+            if reading.latency > threshold(reading) {
                 intensity += 1;
-            } else {
-                reading_history.push((false, i, reading));
             }
+            window.push_back((i, reading));
 
-            while let Some(first_reading) = reading_history.first() {
-                let time_since = reading.timestamp.duration_since(first_reading.2.timestamp);
+            while let Some((_, first_reading)) = window.front() {
+                let time_since = reading.timestamp.duration_since(first_reading.timestamp);
                 match time_since {
-                    Ok(time_since) => {
-                        if time_since > self.max_window {
-                            let (over_threshold, _, _) = reading_history.remove(0);
-
-                            if over_threshold {
-                                intensity -= 1;
-                            }
-                        } else {
-                            break;
-                        }
-                    }
+                    Ok(time_since) if time_since <= self.max_window => break,
+                    Ok(_) => {}
                     Err(e) => {
                         log::error!("Time skip anomaly in ping reading, skipping: {e}",);
-                        let (over_threshold, _, _) = reading_history.remove(0);
+                    }
+                }
 
-                        if over_threshold {
-                            intensity -= 1;
-                        }
+                if let Some((_, first_reading)) = window.pop_front() {
+                    if first_reading.latency > threshold(first_reading) {
+                        intensity -= 1;
                     }
+                    evicted.push_back(first_reading);
                 }
             }
 
-            if intensity > self.min_intensity as usize {
-                included_readings.extend(reading_history.iter().map(|(_, index, _)| index))
+            if let Some((_, window_start)) = window.front() {
+                while evicted.front().is_some_and(|evicted_reading| {
+                    window_start
+                        .timestamp
+                        .duration_since(evicted_reading.timestamp)
+                        .map_or(true, |time_before| time_before > self.max_window)
+                }) {
+                    evicted.pop_front();
+                }
             }
-        }
 
-        let mut ordered_included_readings: Vec<usize> = included_readings.into_iter().collect();
+            if intensity > self.min_intensity as usize {
+                let (window_start_index, _) = window[0];
 
-        ordered_included_readings.sort();
+                match open_episode.as_mut() {
+                    Some(episode) if window_start_index <= episode.last_index + 1 => {
+                        episode.extend(&window);
+                    }
+                    _ => {
+                        closing_episodes.extend(open_episode.take());
+                        open_episode = Some(EpisodeBuilder::new(&window, &evicted));
+                    }
+                }
+            }
 
-        ordered_included_readings
+            /* Episodes end in order, so the ones still collecting the readings
+             * after them are only ever completed from the front.
+             */
+            for episode in closing_episodes.iter_mut().chain(open_episode.as_mut()) {
+                if episode.last_index < i {
+                    episode.add_reading_after(reading, self.max_window);
+                }
+            }
+            while closing_episodes
+                .front()
+                .is_some_and(|episode| episode.readings_after_complete)
+            {
+                closed_episodes.extend(closing_episodes.pop_front());
+            }
+        }
+
+        closed_episodes
             .into_iter()
-            .map(|index| readings.get(index).unwrap())
-            .cloned()
+            .chain(closing_episodes)
+            .chain(open_episode)
+            .map(|episode| episode.build(&threshold))
             .collect()
     }
 }
 
+/* Accumulates the readings of an episode as the query walks the readings,
+ * along with the readings before and after it that make up its baseline.
+ */
+struct EpisodeBuilder<'a> {
+    readings: Vec<&'a PingReading>,
+    last_index: usize,
+    readings_before: Vec<&'a PingReading>,
+    readings_after: Vec<&'a PingReading>,
+    readings_after_complete: bool,
+}
+
+impl<'a> EpisodeBuilder<'a> {
+    fn new(
+        window: &VecDeque<(usize, &'a PingReading)>,
+        evicted: &VecDeque<&'a PingReading>,
+    ) -> EpisodeBuilder<'a> {
+        EpisodeBuilder {
+            readings: window.iter().map(|(_, reading)| *reading).collect(),
+            last_index: window.back().map_or(0, |(index, _)| *index),
+            readings_before: evicted.iter().copied().collect(),
+            readings_after: vec![],
+            readings_after_complete: false,
+        }
+    }
+
+    /* The window holds consecutive readings, so the ones the episode does not
+     * have yet are at its end, usually just the newest one.
+     */
+    fn extend(&mut self, window: &VecDeque<(usize, &'a PingReading)>) {
+        let Some((newest_index, _)) = window.back() else {
+            return;
+        };
+        let new_readings = newest_index
+            .saturating_sub(self.last_index)
+            .min(window.len());
+
+        self.readings.extend(
+            window
+                .range(window.len() - new_readings..)
+                .map(|(_, reading)| *reading),
+        );
+        self.last_index = *newest_index;
+        self.readings_after.clear();
+        self.readings_after_complete = false;
+    }
+
+    fn add_reading_after(&mut self, reading: &'a PingReading, max_window: Duration) {
+        if self.readings_after_complete {
+            return;
+        }
+
+        let end = self.readings[self.readings.len() - 1].timestamp;
+        if reading
+            .timestamp
+            .duration_since(end)
+            .is_ok_and(|time_after| time_after <= max_window)
+        {
+            self.readings_after.push(reading);
+        } else {
+            self.readings_after_complete = true;
+        }
+    }
+
+    fn build<F: Fn(&PingReading) -> Duration>(self, threshold: &F) -> PingEpisode {
+        let start = self.readings[0].timestamp;
+        let end = self.readings[self.readings.len() - 1].timestamp;
+
+        PingEpisode {
+            start,
+            end,
+            duration: end.duration_since(start).unwrap_or_default(),
+            over_threshold_count: self
+                .readings
+                .iter()
+                .filter(|reading| reading.latency > threshold(reading))
+                .count(),
+            peak_latency: self
+                .readings
+                .iter()
+                .map(|reading| reading.latency)
+                .max()
+                .unwrap_or_default(),
+            median_latency: median_latency(self.readings.iter().copied()).unwrap_or_default(),
+            baseline_latency: median_latency(
+                self.readings_before.into_iter().chain(self.readings_after),
+            ),
+            readings: self.readings.into_iter().cloned().collect(),
+        }
+    }
+}
+
+fn median_latency<'a, I: Iterator<Item = &'a PingReading>>(readings: I) -> Option<Duration> {
+    let mut latencies: Vec<Duration> = readings.map(|reading| reading.latency).collect();
+    latencies.sort();
+
+    latencies.get(latencies.len() / 2).copied()
+}
+
+/* A contiguous run of readings matched by a `PingReadingQuery`, along with
+ * a summary so that clients can list incidents without walking the readings.
+ * The baseline is the median latency of the readings within the query's
+ * window before and after the episode.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PingEpisode {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub duration: Duration,
+    pub over_threshold_count: usize,
+    pub peak_latency: Duration,
+    pub median_latency: Duration,
+    pub baseline_latency: Option<Duration>,
+    pub readings: Vec<PingReading>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PingReading {
     pub latency: Duration,
     pub timestamp: SystemTime,
+    pub sequence: Option<u64>,
     pub original_line: String,
 }
 
+/* Why the ping process last stopped */
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProbeError {
+    pub time: SystemTime,
+    pub message: String,
+}
+
 #[derive(Debug)]
 pub struct PingReadingHistory {
-    readings: Vec<PingReading>,
+    readings: VecDeque<Arc<PingReading>>,
     max_readings: usize,
+    subscribers: Vec<Subscriber>,
+    metrics: TargetMetrics,
+    probe_running: bool,
+    last_probe_error: Option<ProbeError>,
+    anomaly_detector: AnomalyDetector,
 }
 
 impl PingReadingHistory {
-    fn new(interval_seconds: f32, history_length: Duration) -> Self {
+    pub fn new(
+        interval_seconds: f32,
+        history_length: Duration,
+        anomaly_config: AnomalyConfig,
+    ) -> Self {
         PingReadingHistory {
             readings: Default::default(),
             max_readings: Self::calculate_max_readings(interval_seconds, history_length),
+            subscribers: vec![],
+            metrics: TargetMetrics::default(),
+            probe_running: false,
+            last_probe_error: None,
+            anomaly_detector: AnomalyDetector::new(anomaly_config),
         }
     }
 
@@ -113,6 +301,28 @@ impl PingReadingHistory {
         usize::max((history_length_ms as f32 / interval_millis) as usize, 1)
     }
 
+    /* Keeps the newest readings that still fit */
+    pub fn resize(&mut self, interval_seconds: f32, history_length: Duration) {
+        self.max_readings = Self::calculate_max_readings(interval_seconds, history_length);
+
+        while self.readings.len() > self.max_readings {
+            self.readings.pop_front();
+        }
+    }
+
+    fn parse_sequence(line: &str) -> Option<u64> {
+        /* The sequence number is optional, we expect it to be in the form
+         * ... icmp_seq=NUMBER ...
+         */
+        let sequence_and_rest = line.split("icmp_seq=").nth(1)?;
+        let sequence: String = sequence_and_rest
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+
+        sequence.parse().ok()
+    }
+
     fn parse_line_into_reading(line: &str) -> Option<PingReading> {
         /* We expect any ping reading lines to be in the form
          * ... time=NUMBER ms ...
@@ -123,6 +333,7 @@ impl PingReadingHistory {
                     return Some(PingReading {
                         latency: Duration::from_millis(time as u64),
                         timestamp: SystemTime::now(),
+                        sequence: PingReadingHistory::parse_sequence(line),
                         original_line: line.to_string(),
                     });
                 }
@@ -132,21 +343,109 @@ impl PingReadingHistory {
     }
 
     fn add_reading(&mut self, ping_reading: PingReading) {
-        self.readings.push(ping_reading);
+        self.metrics.record_reading(&ping_reading);
+        self.anomaly_detector.observe(&ping_reading);
+
+        self.subscribers
+            .retain_mut(|subscriber| subscriber.notify(&ping_reading));
 
-        if self.readings.len() > self.max_readings {
-            self.readings.remove(0);
+        self.readings.push_back(Arc::new(ping_reading));
+
+        while self.readings.len() > self.max_readings {
+            self.readings.pop_front();
         }
     }
 
     pub fn add_output_line(&mut self, line: &str) {
         if let Some(reading) = PingReadingHistory::parse_line_into_reading(line) {
             self.add_reading(reading);
+        } else if line.contains("bytes from") {
+            /* Replies always have this, anything else is a header, summary
+             * or error line that is not expected to hold a reading.
+             */
+            self.metrics.record_parse_failure();
         }
     }
 
-    pub fn readings(&self) -> &[PingReading] {
-        &self.readings
+    /* Readings are shared with the history, so a snapshot is cheap to take
+     * and queries can run on it without holding the history's lock.
+     */
+    pub fn snapshot(&self) -> Vec<Arc<PingReading>> {
+        self.readings.iter().cloned().collect()
+    }
+
+    /* Walks back from the newest reading, so it stays cheap on long histories */
+    pub fn readings_since(&self, start: SystemTime) -> Vec<Arc<PingReading>> {
+        let mut readings: Vec<Arc<PingReading>> = self
+            .readings
+            .iter()
+            .rev()
+            .take_while(|reading| reading.timestamp >= start)
+            .cloned()
+            .collect();
+        readings.reverse();
+        readings
+    }
+
+    /* Monitor tasks that are cancelled cannot clear this themselves */
+    pub fn set_probe_stopped(&mut self) {
+        self.probe_running = false;
+    }
+
+    pub fn set_anomaly_config(&mut self, anomaly_config: AnomalyConfig) {
+        self.anomaly_detector.set_config(anomaly_config);
+    }
+
+    pub fn anomaly_events(&self, range: &TimeRange) -> Vec<AnomalyEvent> {
+        self.anomaly_detector.events(range)
+    }
+
+    pub fn seasonal_baseline(&self) -> &SeasonalBaseline {
+        self.anomaly_detector.seasonal_baseline()
+    }
+
+    pub fn seasonal_baseline_mut(&mut self) -> &mut SeasonalBaseline {
+        self.anomaly_detector.seasonal_baseline_mut()
+    }
+
+    pub fn level_shifts(&self, range: &TimeRange) -> Vec<LevelShift> {
+        self.anomaly_detector.level_shifts(range)
+    }
+
+    pub fn is_probe_running(&self) -> bool {
+        self.probe_running
+    }
+
+    pub fn last_probe_error(&self) -> Option<&ProbeError> {
+        self.last_probe_error.as_ref()
+    }
+
+    pub fn reading_count(&self) -> usize {
+        self.readings.len()
+    }
+
+    /* An estimate of the heap used by the readings, readings shared with a
+     * snapshot are only counted here.
+     */
+    pub fn memory_usage(&self) -> usize {
+        let reading_size = 2 * size_of::<usize>() + size_of::<PingReading>();
+
+        size_of::<Self>()
+            + self.readings.capacity() * size_of::<Arc<PingReading>>()
+            + self
+                .readings
+                .iter()
+                .map(|reading| reading_size + reading.original_line.capacity())
+                .sum::<usize>()
+            + self.subscribers.capacity() * size_of::<Subscriber>()
+    }
+
+    pub fn subscribe(&mut self, subscriber: Subscriber) {
+        self.subscribers.push(subscriber);
+    }
+
+    pub fn metrics(&self) -> &TargetMetrics {
+        &self.metrics
     }
 }
 
@@ -161,15 +460,12 @@ impl PingMonitor {
     pub fn new(
         target_host: String,
         interval_seconds: f32,
-        history_length: Duration,
+        ping_reading_history: Arc<Mutex<PingReadingHistory>>,
     ) -> PingMonitor {
         PingMonitor {
             target_host,
             interval_seconds,
-            ping_reading_history: Arc::new(Mutex::new(PingReadingHistory::new(
-                interval_seconds,
-                history_length,
-            ))),
+            ping_reading_history,
         }
     }
 
@@ -184,11 +480,12 @@ impl PingMonitor {
             .arg(target_host)
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
         c
     }
 
-    async fn _watch(&mut self) {
+    async fn _watch(&mut self) -> anyhow::Result<()> {
         let ping_command = PingMonitor::create_command(self.interval_seconds, &self.target_host);
 
         watch(
@@ -201,22 +498,111 @@ impl PingMonitor {
                     .add_output_line(line);
                 InputConsumptionResult::Continue
             },
-            |_| InputConsumptionResult::TerminateCommand {
-                reason: String::from("ping failed"),
+            |line| InputConsumptionResult::TerminateCommand {
+                reason: match line.trim_end() {
+                    "" => String::from("ping exited"),
+                    line => line.to_string(),
+                },
             },
         )
-        .await;
+        .await
     }
 
     pub async fn watch(&mut self) -> anyhow::Result<()> {
         loop {
-            self._watch().await;
+            self.ping_reading_history.lock().unwrap().probe_running = true;
+            let result = self._watch().await;
+
+            {
+                let mut history = self.ping_reading_history.lock().unwrap();
+                history.probe_running = false;
+                if let Err(e) = result {
+                    history.last_probe_error = Some(ProbeError {
+                        time: SystemTime::now(),
+                        message: e.to_string(),
+                    });
+                }
+            }
+
             log::error!("Ping command stopped, waiting to retry");
             Timer::after(Duration::from_secs(5)).await;
+            self.ping_reading_history
+                .lock()
+                .unwrap()
+                .metrics
+                .record_probe_restart();
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readings(latencies_ms: &[u64]) -> Vec<PingReading> {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        latencies_ms
+            .iter()
+            .enumerate()
+            .map(|(i, latency_ms)| PingReading {
+                latency: Duration::from_millis(*latency_ms),
+                timestamp: start + Duration::from_secs(i as u64),
+                sequence: Some(i as u64),
+                original_line: String::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn query_finds_separate_episodes_with_baselines() {
+        let mut latencies = vec![10; 100];
+        latencies[20..25].fill(200);
+        latencies[60..63].fill(300);
+        let readings = readings(&latencies);
+
+        let query = PingReadingQuery::new(Duration::from_millis(100), 1, Duration::from_secs(5));
+        let episodes = query.query(&readings);
+
+        assert_eq!(episodes.len(), 2);
+        assert_eq!(episodes[0].over_threshold_count, 5);
+        assert_eq!(episodes[0].peak_latency, Duration::from_millis(200));
+        assert_eq!(
+            episodes[0].baseline_latency,
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(episodes[1].over_threshold_count, 3);
+        assert_eq!(episodes[1].peak_latency, Duration::from_millis(300));
+        assert!(episodes[0].end < episodes[1].start);
+    }
+
+    #[test]
+    fn query_with_no_readings_over_threshold_is_empty() {
+        let readings = readings(&[10; 50]);
+        let query = PingReadingQuery::new(Duration::from_millis(100), 0, Duration::from_secs(5));
+
+        assert!(query.query(&readings).is_empty());
+    }
 
-    pub fn reading_history(&self) -> Arc<Mutex<PingReadingHistory>> {
-        self.ping_reading_history.clone()
+    /* Every reading of a long episode is added once, however long the
+     * window it is extended by.
+     */
+    #[test]
+    fn long_windows_add_each_reading_once() {
+        let mut latencies = vec![10; 2000];
+        latencies[500..1500].fill(200);
+        let readings = readings(&latencies);
+
+        let query =
+            PingReadingQuery::new(Duration::from_millis(100), 1, Duration::from_secs(60 * 60));
+        let episodes = query.query(&readings);
+
+        assert_eq!(episodes.len(), 1);
+        let episode_readings = &episodes[0].readings;
+        assert!(episode_readings
+            .windows(2)
+            .all(|pair| pair[0].sequence.unwrap() + 1 == pair[1].sequence.unwrap()));
+        assert_eq!(episode_readings.len(), readings.len());
+        assert_eq!(episodes[0].over_threshold_count, 1000);
     }
 }
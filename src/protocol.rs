@@ -0,0 +1,175 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::util::decode_with_limit;
+
+/* The handshake and envelope formats must stay the same across protocol
+ * versions, they are what lets mismatched clients and servers detect each
+ * other instead of misreading messages.
+ */
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"OXNT";
+
+/* Version 3 added anomaly detection settings to monitor configs, including
+ * the level shift ones and relative thresholds, which are sent in query
+ * results and by clients adding targets, and whether anomaly events are
+ * relative, so version 2 peers cannot decode them.
+ */
+pub const PROTOCOL_VERSION: u32 = 3;
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+pub const CAPABILITY_EPISODES: &str = "episodes";
+pub const CAPABILITY_STATISTICS: &str = "statistics";
+pub const CAPABILITY_COMPARISON: &str = "comparison";
+pub const CAPABILITY_SLA: &str = "sla";
+pub const CAPABILITY_HISTOGRAM: &str = "histogram";
+pub const CAPABILITY_HEATMAP: &str = "heatmap";
+pub const CAPABILITY_SUBSCRIBE: &str = "subscribe";
+pub const CAPABILITY_MANAGE_TARGETS: &str = "manage-targets";
+pub const CAPABILITY_STATUS: &str = "status";
+pub const CAPABILITY_DIAGNOSTICS: &str = "diagnostics";
+pub const CAPABILITY_ANOMALIES: &str = "anomalies";
+pub const CAPABILITY_LEVEL_SHIFTS: &str = "level-shifts";
+pub const CAPABILITY_SEASONAL: &str = "seasonal";
+pub const CAPABILITY_ALERTS: &str = "alerts";
+
+pub const SERVER_CAPABILITIES: &[&str] = &[
+    CAPABILITY_EPISODES,
+    CAPABILITY_STATISTICS,
+    CAPABILITY_COMPARISON,
+    CAPABILITY_SLA,
+    CAPABILITY_HISTOGRAM,
+    CAPABILITY_HEATMAP,
+    CAPABILITY_SUBSCRIBE,
+    CAPABILITY_MANAGE_TARGETS,
+    CAPABILITY_STATUS,
+    CAPABILITY_DIAGNOSTICS,
+    CAPABILITY_ANOMALIES,
+    CAPABILITY_LEVEL_SHIFTS,
+    CAPABILITY_SEASONAL,
+    CAPABILITY_ALERTS,
+];
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProtocolError {
+    #[error("peer is not an oxidenet client or service")]
+    NotOxidenet,
+    #[error("incompatible protocol versions, we support {local_min}-{local_max} and the peer supports {remote_min}-{remote_max}")]
+    IncompatibleVersion {
+        local_min: u32,
+        local_max: u32,
+        remote_min: u32,
+        remote_max: u32,
+    },
+    #[error("expected a message with protocol version {expected} but got {actual}")]
+    UnexpectedVersion { expected: u32, actual: u32 },
+    #[error("could not decode message, the peer may be running a newer version: {0}")]
+    UndecodableMessage(bincode::Error),
+    #[error("handshake rejected by the service: {0}")]
+    Rejected(String),
+    #[error("the service does not support '{0}', it may need to be upgraded")]
+    MissingCapability(&'static str),
+}
+
+/* Capabilities are strings rather than an enum so that capabilities added in
+ * newer versions still decode on older peers.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub magic: [u8; 4],
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub software_version: String,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(capabilities: &[&str]) -> Hello {
+        Hello {
+            magic: PROTOCOL_MAGIC,
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    pub fn negotiate(&self, remote: &Hello) -> Result<u32, ProtocolError> {
+        if remote.magic != PROTOCOL_MAGIC {
+            return Err(ProtocolError::NotOxidenet);
+        }
+
+        let version = u32::min(self.protocol_version, remote.protocol_version);
+
+        if version < u32::max(self.min_protocol_version, remote.min_protocol_version) {
+            return Err(ProtocolError::IncompatibleVersion {
+                local_min: self.min_protocol_version,
+                local_max: self.protocol_version,
+                remote_min: remote.min_protocol_version,
+                remote_max: remote.protocol_version,
+            });
+        }
+
+        Ok(version)
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/* Sent by the server in reply to the client's hello, `result` holds the
+ * negotiated protocol version or the reason the client was rejected.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HelloResponse {
+    pub server: Hello,
+    pub result: Result<u32, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope {
+    pub protocol_version: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn seal<T: Serialize>(protocol_version: u32, message: &T) -> anyhow::Result<Envelope> {
+        Ok(Envelope {
+            protocol_version,
+            payload: bincode::serialize(message)?,
+        })
+    }
+
+    pub fn open<T: DeserializeOwned>(&self, protocol_version: u32) -> Result<T, ProtocolError> {
+        if self.protocol_version != protocol_version {
+            return Err(ProtocolError::UnexpectedVersion {
+                expected: protocol_version,
+                actual: self.protocol_version,
+            });
+        }
+
+        decode_with_limit(&self.payload, self.payload.len() as u64)
+            .map_err(ProtocolError::UndecodableMessage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_before_anomaly_settings_are_rejected() {
+        let local = Hello::new(SERVER_CAPABILITIES);
+        let remote = Hello {
+            protocol_version: 2,
+            min_protocol_version: 2,
+            ..Hello::new(&[])
+        };
+
+        assert!(matches!(
+            local.negotiate(&remote),
+            Err(ProtocolError::IncompatibleVersion { .. })
+        ));
+        assert_eq!(local.negotiate(&Hello::new(&[])).unwrap(), PROTOCOL_VERSION);
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use futures_rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use futures_rustls::rustls::server::WebPkiClientVerifier;
+use futures_rustls::rustls::{
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, StreamOwned,
+};
+use futures_rustls::TlsAcceptor;
+
+use crate::config::RemoteConfig;
+
+fn load_certificates(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let mut reader =
+        BufReader::new(File::open(path).map_err(|e| {
+            anyhow::anyhow!("Could not open certificate file {}: {e}", path.display())
+        })?);
+
+    let certificates = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+
+    if certificates.is_empty() {
+        anyhow::bail!("No certificates found in {}", path.display());
+    }
+
+    Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let mut reader =
+        BufReader::new(File::open(path).map_err(|e| {
+            anyhow::anyhow!("Could not open private key file {}: {e}", path.display())
+        })?);
+
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", path.display()))
+}
+
+pub fn load_root_store(path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    for certificate in load_certificates(path)? {
+        roots.add(certificate)?;
+    }
+
+    Ok(roots)
+}
+
+/* Client certificates are optional when a client CA is configured, clients
+ * without one have to authenticate with a token instead.
+ */
+pub fn create_tls_acceptor(remote_config: &RemoteConfig) -> anyhow::Result<TlsAcceptor> {
+    let builder = ServerConfig::builder();
+
+    let builder = match &remote_config.client_ca {
+        Some(client_ca) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(load_root_store(client_ca)?))
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder.with_single_cert(
+        load_certificates(&remote_config.certificate)?,
+        load_private_key(&remote_config.private_key)?,
+    )?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/* Compares every byte regardless of where the first difference is, so the
+ * time taken does not reveal how much of a token was guessed correctly.
+ */
+pub fn is_valid_token(remote_config: &RemoteConfig, token: &str) -> bool {
+    remote_config.tokens.iter().fold(false, |valid, candidate| {
+        let matches = candidate.len() == token.len()
+            && candidate
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0;
+        valid | matches
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct RemoteOptions {
+    pub address: String,
+    pub server_name: Option<String>,
+    pub ca_certificate: Option<PathBuf>,
+    pub client_certificate: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub token: Option<String>,
+}
+
+pub fn connect_tls(
+    options: &RemoteOptions,
+) -> anyhow::Result<StreamOwned<ClientConnection, TcpStream>> {
+    let Some(ca_certificate) = &options.ca_certificate else {
+        anyhow::bail!("A CA certificate is required to verify a remote service");
+    };
+
+    let builder = ClientConfig::builder().with_root_certificates(load_root_store(ca_certificate)?);
+
+    let client_config = match (&options.client_certificate, &options.client_key) {
+        (Some(certificate), Some(key)) => builder
+            .with_client_auth_cert(load_certificates(certificate)?, load_private_key(key)?)?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => anyhow::bail!("A client certificate and key must be given together"),
+    };
+
+    let server_name = options.server_name.clone().unwrap_or_else(|| {
+        options
+            .address
+            .rsplit_once(':')
+            .map_or(options.address.as_str(), |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string()
+    });
+
+    let connection =
+        ClientConnection::new(Arc::new(client_config), ServerName::try_from(server_name)?)?;
+
+    let stream = TcpStream::connect(&options.address).map_err(|e| {
+        anyhow::anyhow!(
+            "Could not connect to the monitor service at {}: {e}",
+            options.address
+        )
+    })?;
+
+    Ok(StreamOwned::new(connection, stream))
+}
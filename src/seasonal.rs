@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+use serde::{Deserialize, Serialize};
+
+use crate::histogram::{HeatmapCell, LatencyHeatmap};
+use crate::util::{read_state_file, write_state_file};
+
+pub const HOURS_PER_WEEK: usize = 7 * 24;
+
+/* Each hour of the week is averaged over about this many weeks, so that the
+ * usual latency follows lasting changes without forgetting last week.
+ */
+const SEASONAL_WEEKS: f64 = 4.0;
+
+/* Fast monitors would otherwise keep a lot of readings for the current hour,
+ * the median of the first ones is close enough.
+ */
+const MAX_HOUR_READINGS: usize = 10_000;
+
+/* Local hour of the week, 0 is the hour starting at midnight on monday like
+ * the heatmap.
+ */
+pub fn hour_of_week(time: SystemTime) -> usize {
+    let time = DateTime::<Local>::from(time);
+
+    time.weekday().num_days_from_monday() as usize * 24 + time.hour() as usize
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
+pub struct SeasonalSlot {
+    weeks: u32,
+    #[serde(rename = "usual-ms")]
+    usual_ms: f64,
+}
+
+/* The usual latency for every hour of the week, learned from the median of
+ * each hour as it ends. An hour has no usual latency until it has been seen
+ * once.
+ */
+#[derive(Debug)]
+pub struct SeasonalBaseline {
+    slots: Vec<SeasonalSlot>,
+    current_slot: Option<usize>,
+    current_hour: Vec<f64>,
+}
+
+impl Default for SeasonalBaseline {
+    fn default() -> Self {
+        SeasonalBaseline {
+            slots: vec![SeasonalSlot::default(); HOURS_PER_WEEK],
+            current_slot: None,
+            current_hour: vec![],
+        }
+    }
+}
+
+impl SeasonalBaseline {
+    pub fn observe(&mut self, time: SystemTime, latency_ms: f64) {
+        let slot = hour_of_week(time);
+
+        if self.current_slot != Some(slot) {
+            self.finish_hour();
+            self.current_slot = Some(slot);
+        }
+
+        if self.current_hour.len() < MAX_HOUR_READINGS {
+            self.current_hour.push(latency_ms);
+        }
+    }
+
+    fn finish_hour(&mut self) {
+        let Some(slot) = self.current_slot else {
+            return;
+        };
+        if self.current_hour.is_empty() {
+            return;
+        }
+
+        self.current_hour.sort_by(f64::total_cmp);
+        let median = self.current_hour[self.current_hour.len() / 2];
+        self.current_hour.clear();
+
+        let slot = &mut self.slots[slot];
+        slot.weeks += 1;
+        let alpha = (1.0 / slot.weeks as f64).max(1.0 / SEASONAL_WEEKS);
+        slot.usual_ms += alpha * (median - slot.usual_ms);
+    }
+
+    pub fn usual_ms(&self, time: SystemTime) -> Option<f64> {
+        let slot = self.slots[hour_of_week(time)];
+
+        (slot.weeks > 0).then_some(slot.usual_ms)
+    }
+
+    /* A copy of the usual latency of every hour, for queries that look it up
+     * for many readings without holding the history's lock.
+     */
+    pub fn usual_by_hour(&self) -> Vec<Option<f64>> {
+        self.slots
+            .iter()
+            .map(|slot| (slot.weeks > 0).then_some(slot.usual_ms))
+            .collect()
+    }
+
+    /* The hour in progress is not included, it is learned again after a
+     * restart.
+     */
+    pub fn slots(&self) -> &[SeasonalSlot] {
+        &self.slots
+    }
+
+    pub fn restore(&mut self, slots: &[SeasonalSlot]) {
+        if slots.len() == HOURS_PER_WEEK && slots.iter().all(|slot| slot.usual_ms.is_finite()) {
+            self.slots = slots.to_vec();
+        }
+    }
+
+    /* Cell counts are the number of weeks each hour was learned from */
+    pub fn heatmap(&self) -> LatencyHeatmap {
+        LatencyHeatmap {
+            cells: self
+                .slots
+                .chunks(24)
+                .map(|hours| {
+                    hours
+                        .iter()
+                        .map(|slot| HeatmapCell {
+                            count: slot.weeks as usize,
+                            mean_ms: (slot.weeks > 0).then_some(slot.usual_ms),
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
+
+/* Baselines take weeks to learn, so they are saved per target and restored
+ * when the service starts. A missing file is an empty one.
+ */
+pub fn load_seasonal_baselines(path: &Path) -> anyhow::Result<HashMap<String, Vec<SeasonalSlot>>> {
+    read_state_file(path, "seasonal baselines")
+}
+
+pub fn save_seasonal_baselines(
+    path: &Path,
+    baselines: &HashMap<String, Vec<SeasonalSlot>>,
+) -> anyhow::Result<()> {
+    write_state_file(path, &serde_json::to_vec(baselines)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn learned_baselines_survive_saving_and_loading() {
+        let hour = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut baseline = SeasonalBaseline::default();
+        for latency_ms in [10.0, 12.0, 11.0] {
+            baseline.observe(hour, latency_ms);
+        }
+        baseline.observe(hour + Duration::from_secs(60 * 60), 50.0);
+        assert_eq!(baseline.usual_ms(hour), Some(11.0));
+
+        let path =
+            std::env::temp_dir().join(format!("oxidenet-seasonal-{}.json", std::process::id()));
+        let baselines = HashMap::from([(String::from("example.com"), baseline.slots().to_vec())]);
+        save_seasonal_baselines(&path, &baselines).unwrap();
+        let loaded = load_seasonal_baselines(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut restored = SeasonalBaseline::default();
+        restored.restore(&loaded["example.com"]);
+        assert_eq!(restored.usual_ms(hour), Some(11.0));
+        assert_eq!(restored.usual_ms(hour + Duration::from_secs(60 * 60)), None);
+    }
+}
@@ -2,30 +2,107 @@ use serde::{Deserialize, Serialize};
 
 use std::{
     collections::HashMap,
-    path::Path,
-    sync::{Arc, Mutex},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
 };
 
-use smol::net::unix::{UnixListener, UnixStream};
+use smol::future::FutureExt;
+use smol::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use smol::lock::{Semaphore, SemaphoreGuardArc};
+use smol::net::TcpListener;
 use smol::stream::StreamExt;
 
 use crate::{
+    access::{bind_socket, PeerAccess, PeerCredentials},
+    alerts::{AlertEngine, AlertReport},
+    anomaly::AnomalyEvent,
+    changepoint::LevelShiftReport,
     client::ClientCommand,
-    config::{Config, PingMonitorConfig},
-    ping::{PingReading, PingReadingHistory, PingReadingQuery},
-    util::{receive_length_prefixed_object_async, send_length_prefixed_object_async},
+    config::{Config, PingMonitorConfig, RemoteConfig},
+    diagnostics::{collect_diagnostics, ServiceDiagnostics},
+    health::TargetStatus,
+    histogram::{LatencyHeatmap, LatencyHistogram},
+    monitor::{Monitors, SelectedTarget},
+    notify::Notifier,
+    ping::{PingEpisode, PingReading, PingReadingQuery},
+    protocol::{Envelope, Hello, HelloResponse, SERVER_CAPABILITIES},
+    remote::{create_tls_acceptor, is_valid_token},
+    seasonal::hour_of_week,
+    sla::{SlaPeriod, SlaReport},
+    stats::{PeriodComparison, PeriodStatistics, PingStatistics, TimeRange},
+    subscription::{Subscriber, SubscriptionEvent, SUBSCRIPTION_BUFFER_SIZE},
+    util::{
+        receive_length_prefixed_object_async_with_timeouts, send_length_prefixed_object_async,
+        with_timeout,
+    },
 };
 
+/* Sent instead of a result when a command could not be answered, the
+ * connection stays open afterwards.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ServerError {
+    #[error("unknown target {0}")]
+    UnknownTarget(String),
+    #[error("invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("internal error: {0}")]
+    Internal(String),
+    #[error("unsupported command: {0}")]
+    UnsupportedCommand(String),
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("invalid target: {0}")]
+    InvalidTarget(String),
+}
+
+/* Histograms get a bucket per boundary, this keeps a single query from
+ * allocating without bound.
+ */
+pub const MAX_HISTOGRAM_BUCKETS: usize = 1000;
+
 #[derive(Serialize, Deserialize)]
 pub enum ServerResponse {
-    UnknownTarget(String),
-    PingQueryResult(HashMap<String, (Vec<PingReading>, PingMonitorConfig)>),
+    Error(ServerError),
+    PingQueryResult(HashMap<String, (Vec<PingEpisode>, PingMonitorConfig)>),
+    StatisticsResult(HashMap<String, Option<PingStatistics>>),
+    ComparisonResult(HashMap<String, PeriodComparison>),
+    SlaResult(HashMap<String, SlaReport>),
+    HistogramResult(HashMap<String, LatencyHistogram>),
+    HeatmapResult(HashMap<String, LatencyHeatmap>),
+    Subscribed(Vec<String>),
+    SubscriptionEvent(SubscriptionEvent),
+    AuthenticationResult(Result<(), String>),
+    TargetUpdated(String),
+    StatusResult(HashMap<String, TargetStatus>),
+    DiagnosticsResult(ServiceDiagnostics),
+    AnomalyResult(HashMap<String, Vec<AnomalyEvent>>),
+    LevelShiftResult(HashMap<String, Vec<LevelShiftReport>>),
+    AlertResult(AlertReport),
 }
 
-#[derive(Default, Debug)]
+/* The config is replaced when it is reloaded, so it is read through
+ * `config()` each time it is needed.
+ */
+#[derive(Debug)]
 pub struct ServerState {
-    pub ping_reading_histories: HashMap<String, Arc<Mutex<PingReadingHistory>>>,
-    pub config: Config,
+    pub monitors: Mutex<Monitors>,
+    pub alerts: Mutex<AlertEngine>,
+    pub notifier: Mutex<Notifier>,
+    pub config: RwLock<Arc<Config>>,
+    pub config_path: PathBuf,
+    pub started_at: SystemTime,
+    pub connected_clients: AtomicUsize,
+}
+
+impl ServerState {
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
@@ -34,30 +111,154 @@ pub struct TargetAndPingReadingQuery {
     pub query: PingReadingQuery,
 }
 
-fn query_ping_readings_for_targets<
-    'a,
-    I: Iterator<Item = (&'a String, &'a Arc<Mutex<PingReadingHistory>>)>,
->(
-    target_readings: I,
-    query: &PingReadingQuery,
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TargetAndStatisticsQuery {
+    pub target: Option<String>,
+    pub range: TimeRange,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TargetAndComparisonQuery {
+    pub target: Option<String>,
+    pub first: TimeRange,
+    pub second: TimeRange,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TargetAndSlaQuery {
+    pub target: Option<String>,
+    pub range: TimeRange,
+    pub period: SlaPeriod,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TargetAndHistogramQuery {
+    pub target: Option<String>,
+    pub range: TimeRange,
+    pub bucket_boundaries: Vec<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TargetAndHeatmapQuery {
+    pub target: Option<String>,
+    pub range: TimeRange,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TargetSubscription {
+    pub target: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TargetAndAnomalyQuery {
+    pub target: Option<String>,
+    pub range: TimeRange,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TargetAndLevelShiftQuery {
+    pub target: Option<String>,
+    pub range: TimeRange,
+    pub include_readings: bool,
+}
+
+/* Readings over `times_usual` times the usual latency for their hour of the
+ * week are over the threshold, the query's own threshold is used for hours
+ * that have not been seen yet.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TargetAndRelativePingReadingQuery {
+    pub target: Option<String>,
+    pub query: PingReadingQuery,
+    pub times_usual: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TargetAndSeasonalBaselineQuery {
+    pub target: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TargetAndAlertQuery {
+    pub target: Option<String>,
+    pub range: TimeRange,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TargetAndStatusQuery {
+    pub target: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TargetAndMonitorConfig {
+    pub target: String,
+    pub config: PingMonitorConfig,
+}
+
+fn select_targets(
+    target: &Option<String>,
     server_state: &ServerState,
-) -> HashMap<String, (Vec<PingReading>, PingMonitorConfig)> {
+) -> Result<Vec<SelectedTarget>, ServerError> {
+    server_state.monitors.lock().unwrap().select(target)
+}
+
+fn validate_range(range: &TimeRange) -> Result<(), ServerError> {
+    if let (Some(start), Some(end)) = (range.start, range.end) {
+        if start > end {
+            return Err(ServerError::InvalidQuery(String::from(
+                "the start of the time range is after its end",
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn query_statistics_for_targets<I: Iterator<Item = SelectedTarget>>(
+    target_readings: I,
+    range: &TimeRange,
+) -> HashMap<String, Option<PingStatistics>> {
     let mut results = HashMap::new();
 
-    for (target, reading_history) in target_readings {
-        let reading_history = reading_history.lock().unwrap();
+    for SelectedTarget {
+        target,
+        reading_history,
+        ..
+    } in target_readings
+    {
+        let snapshot = reading_history.lock().unwrap().snapshot();
 
-        let target_result = query.query(reading_history.readings());
+        let readings = range.filter(snapshot.iter().map(Arc::as_ref));
+
+        results.insert(target, PingStatistics::from_readings(&readings));
+    }
+
+    results
+}
+
+fn query_comparison_for_targets<I: Iterator<Item = SelectedTarget>>(
+    target_readings: I,
+    first: &TimeRange,
+    second: &TimeRange,
+) -> HashMap<String, PeriodComparison> {
+    let mut results = HashMap::new();
+
+    for SelectedTarget {
+        target,
+        reading_history,
+        ..
+    } in target_readings
+    {
+        let snapshot = reading_history.lock().unwrap().snapshot();
+
+        let readings: Vec<&PingReading> = snapshot.iter().map(Arc::as_ref).collect();
+        let history_start = readings.first().map(|reading| reading.timestamp);
 
         results.insert(
-            target.clone(),
-            (
-                target_result,
-                *server_state
-                    .config
-                    .ping_monitor_configs()
-                    .get(target)
-                    .expect(""),
+            target,
+            PeriodComparison::new(
+                PeriodStatistics::from_readings(*first, &readings, history_start),
+                PeriodStatistics::from_readings(*second, &readings, history_start),
             ),
         );
     }
@@ -65,64 +266,823 @@ fn query_ping_readings_for_targets<
     results
 }
 
-async fn serve_client(mut stream: UnixStream, server_state: &ServerState) -> anyhow::Result<()> {
+fn query_sla_for_targets<I: Iterator<Item = SelectedTarget>>(
+    target_readings: I,
+    range: &TimeRange,
+    period: SlaPeriod,
+) -> HashMap<String, SlaReport> {
+    let mut results = HashMap::new();
+
+    for SelectedTarget {
+        target,
+        config,
+        reading_history,
+        ..
+    } in target_readings
+    {
+        let snapshot = reading_history.lock().unwrap().snapshot();
+
+        let readings = range.filter(snapshot.iter().map(Arc::as_ref));
+        let sla_config = config.sla.unwrap_or_default();
+
+        results.insert(
+            target,
+            SlaReport::from_readings(&readings, sla_config, period),
+        );
+    }
+
+    results
+}
+
+fn query_histogram_for_targets<I: Iterator<Item = SelectedTarget>>(
+    target_readings: I,
+    range: &TimeRange,
+    bucket_boundaries: &[Duration],
+) -> HashMap<String, LatencyHistogram> {
+    let mut results = HashMap::new();
+
+    for SelectedTarget {
+        target,
+        reading_history,
+        ..
+    } in target_readings
+    {
+        let snapshot = reading_history.lock().unwrap().snapshot();
+
+        let readings = range.filter(snapshot.iter().map(Arc::as_ref));
+
+        results.insert(
+            target,
+            LatencyHistogram::from_readings(&readings, bucket_boundaries),
+        );
+    }
+
+    results
+}
+
+fn query_heatmap_for_targets<I: Iterator<Item = SelectedTarget>>(
+    target_readings: I,
+    range: &TimeRange,
+) -> HashMap<String, LatencyHeatmap> {
+    let mut results = HashMap::new();
+
+    for SelectedTarget {
+        target,
+        reading_history,
+        ..
+    } in target_readings
+    {
+        let snapshot = reading_history.lock().unwrap().snapshot();
+
+        let readings = range.filter(snapshot.iter().map(Arc::as_ref));
+
+        results.insert(target, LatencyHeatmap::from_readings(&readings));
+    }
+
+    results
+}
+
+fn query_anomalies_for_targets<I: Iterator<Item = SelectedTarget>>(
+    target_readings: I,
+    range: &TimeRange,
+) -> HashMap<String, Vec<AnomalyEvent>> {
+    let mut results = HashMap::new();
+
+    for SelectedTarget {
+        target,
+        reading_history,
+        ..
+    } in target_readings
+    {
+        let events = reading_history.lock().unwrap().anomaly_events(range);
+
+        results.insert(target, events);
+    }
+
+    results
+}
+
+fn query_level_shifts_for_targets<I: Iterator<Item = SelectedTarget>>(
+    target_readings: I,
+    range: &TimeRange,
+    include_readings: bool,
+) -> HashMap<String, Vec<LevelShiftReport>> {
+    let mut results = HashMap::new();
+
+    for SelectedTarget {
+        target,
+        reading_history,
+        ..
+    } in target_readings
+    {
+        let history = reading_history.lock().unwrap();
+        let reports = history
+            .level_shifts(range)
+            .into_iter()
+            .map(|shift| {
+                /* Older readings may have already left the history */
+                let readings = if include_readings {
+                    history
+                        .readings_since(shift.start)
+                        .iter()
+                        .take_while(|reading| reading.timestamp <= shift.detected_at)
+                        .map(|reading| reading.as_ref().clone())
+                        .collect()
+                } else {
+                    vec![]
+                };
+
+                LevelShiftReport { shift, readings }
+            })
+            .collect();
+
+        results.insert(target, reports);
+    }
+
+    results
+}
+
+fn query_ping_readings_for_targets<I: Iterator<Item = SelectedTarget>>(
+    target_readings: I,
+    query: &PingReadingQuery,
+) -> HashMap<String, (Vec<PingEpisode>, PingMonitorConfig)> {
+    let mut results = HashMap::new();
+
+    for SelectedTarget {
+        target,
+        config,
+        reading_history,
+        ..
+    } in target_readings
+    {
+        let snapshot = reading_history.lock().unwrap().snapshot();
+
+        let target_result = query.query(snapshot.iter().map(Arc::as_ref));
+
+        results.insert(target, (target_result, config));
+    }
+
+    results
+}
+
+/* Thresholds further above the usual latency than this are past any timeout */
+const MAX_TIMES_USUAL: f64 = 1000.0;
+
+fn query_relative_ping_readings_for_targets<I: Iterator<Item = SelectedTarget>>(
+    target_readings: I,
+    query: &PingReadingQuery,
+    times_usual: f64,
+) -> HashMap<String, (Vec<PingEpisode>, PingMonitorConfig)> {
+    let mut results = HashMap::new();
+
+    for SelectedTarget {
+        target,
+        config,
+        reading_history,
+        ..
+    } in target_readings
+    {
+        let (snapshot, usual_by_hour) = {
+            let history = reading_history.lock().unwrap();
+            (
+                history.snapshot(),
+                history.seasonal_baseline().usual_by_hour(),
+            )
+        };
+
+        let target_result =
+            query.query_with_threshold(snapshot.iter().map(Arc::as_ref), |reading| {
+                usual_by_hour[hour_of_week(reading.timestamp)].map_or(
+                    query.latency_higher_than,
+                    |usual_ms| {
+                        Duration::try_from_secs_f64(usual_ms * times_usual / 1000.0)
+                            .unwrap_or(Duration::MAX)
+                    },
+                )
+            });
+
+        results.insert(target, (target_result, config));
+    }
+
+    results
+}
+
+fn query_seasonal_baselines_for_targets<I: Iterator<Item = SelectedTarget>>(
+    target_readings: I,
+) -> HashMap<String, LatencyHeatmap> {
+    let mut results = HashMap::new();
+
+    for SelectedTarget {
+        target,
+        reading_history,
+        ..
+    } in target_readings
+    {
+        let heatmap = reading_history
+            .lock()
+            .unwrap()
+            .seasonal_baseline()
+            .heatmap();
+
+        results.insert(target, heatmap);
+    }
+
+    results
+}
+
+fn query(
+    command: ClientCommand,
+    server_state: &ServerState,
+) -> Result<ServerResponse, ServerError> {
+    let response = match command {
+        ClientCommand::TargetAndPingReadingQuery(TargetAndPingReadingQuery { target, query }) => {
+            ServerResponse::PingQueryResult(query_ping_readings_for_targets(
+                select_targets(&target, server_state)?.into_iter(),
+                &query,
+            ))
+        }
+        ClientCommand::TargetAndRelativePingReadingQuery(TargetAndRelativePingReadingQuery {
+            target,
+            query,
+            times_usual,
+        }) => {
+            if times_usual.is_nan() || times_usual <= 0.0 || times_usual > MAX_TIMES_USUAL {
+                return Err(ServerError::InvalidQuery(format!(
+                    "times the usual latency must be a positive number up to {MAX_TIMES_USUAL}, not {times_usual}"
+                )));
+            }
+            ServerResponse::PingQueryResult(query_relative_ping_readings_for_targets(
+                select_targets(&target, server_state)?.into_iter(),
+                &query,
+                times_usual,
+            ))
+        }
+        ClientCommand::TargetAndStatisticsQuery(TargetAndStatisticsQuery { target, range }) => {
+            validate_range(&range)?;
+            ServerResponse::StatisticsResult(query_statistics_for_targets(
+                select_targets(&target, server_state)?.into_iter(),
+                &range,
+            ))
+        }
+        ClientCommand::TargetAndComparisonQuery(TargetAndComparisonQuery {
+            target,
+            first,
+            second,
+        }) => {
+            validate_range(&first)?;
+            validate_range(&second)?;
+            ServerResponse::ComparisonResult(query_comparison_for_targets(
+                select_targets(&target, server_state)?.into_iter(),
+                &first,
+                &second,
+            ))
+        }
+        ClientCommand::TargetAndSlaQuery(TargetAndSlaQuery {
+            target,
+            range,
+            period,
+        }) => {
+            validate_range(&range)?;
+            ServerResponse::SlaResult(query_sla_for_targets(
+                select_targets(&target, server_state)?.into_iter(),
+                &range,
+                period,
+            ))
+        }
+        ClientCommand::TargetAndHistogramQuery(TargetAndHistogramQuery {
+            target,
+            range,
+            bucket_boundaries,
+        }) => {
+            validate_range(&range)?;
+            if bucket_boundaries.len() > MAX_HISTOGRAM_BUCKETS {
+                return Err(ServerError::InvalidQuery(format!(
+                    "at most {MAX_HISTOGRAM_BUCKETS} histogram buckets are supported"
+                )));
+            }
+            ServerResponse::HistogramResult(query_histogram_for_targets(
+                select_targets(&target, server_state)?.into_iter(),
+                &range,
+                &bucket_boundaries,
+            ))
+        }
+        ClientCommand::TargetAndHeatmapQuery(TargetAndHeatmapQuery { target, range }) => {
+            validate_range(&range)?;
+            ServerResponse::HeatmapResult(query_heatmap_for_targets(
+                select_targets(&target, server_state)?.into_iter(),
+                &range,
+            ))
+        }
+        ClientCommand::TargetAndSeasonalBaselineQuery(TargetAndSeasonalBaselineQuery {
+            target,
+        }) => ServerResponse::HeatmapResult(query_seasonal_baselines_for_targets(
+            select_targets(&target, server_state)?.into_iter(),
+        )),
+        ClientCommand::TargetAndStatusQuery(TargetAndStatusQuery { target }) => {
+            ServerResponse::StatusResult(
+                server_state
+                    .monitors
+                    .lock()
+                    .unwrap()
+                    .status(&target, SystemTime::now())?,
+            )
+        }
+        ClientCommand::TargetAndAnomalyQuery(TargetAndAnomalyQuery { target, range }) => {
+            validate_range(&range)?;
+            ServerResponse::AnomalyResult(query_anomalies_for_targets(
+                select_targets(&target, server_state)?.into_iter(),
+                &range,
+            ))
+        }
+        ClientCommand::TargetAndLevelShiftQuery(TargetAndLevelShiftQuery {
+            target,
+            range,
+            include_readings,
+        }) => {
+            validate_range(&range)?;
+            ServerResponse::LevelShiftResult(query_level_shifts_for_targets(
+                select_targets(&target, server_state)?.into_iter(),
+                &range,
+                include_readings,
+            ))
+        }
+        ClientCommand::TargetAndAlertQuery(TargetAndAlertQuery { target, range }) => {
+            validate_range(&range)?;
+            ServerResponse::AlertResult(server_state.alerts.lock().unwrap().report(&target, &range))
+        }
+        ClientCommand::Diagnostics => {
+            ServerResponse::DiagnosticsResult(collect_diagnostics(server_state))
+        }
+        ClientCommand::AddTarget(TargetAndMonitorConfig { target, config }) => {
+            server_state
+                .monitors
+                .lock()
+                .unwrap()
+                .add(target.clone(), config)?;
+            ServerResponse::TargetUpdated(target)
+        }
+        ClientCommand::RemoveTarget(target) => {
+            server_state.monitors.lock().unwrap().remove(&target)?;
+            ServerResponse::TargetUpdated(target)
+        }
+        ClientCommand::PauseTarget(target) => {
+            server_state.monitors.lock().unwrap().pause(&target)?;
+            ServerResponse::TargetUpdated(target)
+        }
+        ClientCommand::ResumeTarget(target) => {
+            server_state.monitors.lock().unwrap().resume(&target)?;
+            ServerResponse::TargetUpdated(target)
+        }
+        ClientCommand::Authenticate(_)
+        | ClientCommand::Subscribe(_)
+        | ClientCommand::Disconnect => {
+            return Err(ServerError::UnsupportedCommand(String::from(
+                "authentication, subscriptions and disconnects are only supported on connections",
+            )))
+        }
+    };
+
+    Ok(response)
+}
+
+pub fn respond_to_command(command: ClientCommand, server_state: &ServerState) -> ServerResponse {
+    query(command, server_state).unwrap_or_else(ServerResponse::Error)
+}
+
+/* Anything a client can be served over, local sockets and TLS connections */
+trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> ClientStream for S {}
+
+async fn handshake<S: ClientStream>(
+    stream: &mut S,
+    read_timeout: Duration,
+    max_frame_size: u64,
+) -> anyhow::Result<u32> {
+    let client_hello: Hello = receive_length_prefixed_object_async_with_timeouts(
+        stream,
+        read_timeout,
+        read_timeout,
+        max_frame_size,
+    )
+    .await
+    .map_err(|e| anyhow::anyhow!("Client sent an invalid handshake, it may be outdated: {e}"))?;
+
+    let server_hello = Hello::new(SERVER_CAPABILITIES);
+    let negotiated_version = server_hello.negotiate(&client_hello);
+
+    send_length_prefixed_object_async(
+        &HelloResponse {
+            server: server_hello,
+            result: negotiated_version
+                .as_ref()
+                .copied()
+                .map_err(|e| e.to_string()),
+        },
+        stream,
+    )
+    .await?;
+
+    let negotiated_version = negotiated_version?;
+    log::debug!(
+        "Client {} connected with protocol version {negotiated_version}",
+        client_hello.software_version
+    );
+
+    Ok(negotiated_version)
+}
+
+/* Once subscribed the connection only carries events from the server, any
+ * data or the connection closing from the client ends the subscription.
+ */
+async fn serve_subscription<S: ClientStream>(
+    stream: S,
+    target: &Option<String>,
+    protocol_version: u32,
+    server_state: &ServerState,
+) -> anyhow::Result<()> {
+    let (mut client_stream, mut stream) = smol::io::split(stream);
+
+    let selected_targets = match select_targets(target, server_state) {
+        Ok(selected_targets) => selected_targets,
+        Err(e) => {
+            send_length_prefixed_object_async(
+                &Envelope::seal(protocol_version, &ServerResponse::Error(e))?,
+                &mut stream,
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let (sender, receiver) = smol::channel::bounded(SUBSCRIPTION_BUFFER_SIZE);
+    let mut subscribed_targets = vec![];
+
+    for SelectedTarget {
+        target,
+        reading_history,
+        ..
+    } in selected_targets
+    {
+        reading_history
+            .lock()
+            .unwrap()
+            .subscribe(Subscriber::new(target.clone(), sender.clone()));
+        subscribed_targets.push(target);
+    }
+    drop(sender);
+
+    send_length_prefixed_object_async(
+        &Envelope::seal(
+            protocol_version,
+            &ServerResponse::Subscribed(subscribed_targets),
+        )?,
+        &mut stream,
+    )
+    .await?;
+
+    let mut client_closed = Box::pin(async move {
+        let _ = client_stream.read(&mut [0]).await;
+    });
+    let write_timeout = server_state.config().query_server.read_timeout();
+
     loop {
-        let command: anyhow::Result<ClientCommand> =
-            receive_length_prefixed_object_async(&mut stream).await;
-        match command {
-            Ok(command) => match command {
-                ClientCommand::TargetAndPingReadingQuery(TargetAndPingReadingQuery {
-                    target,
-                    query,
-                }) => {
-                    let result = if let Some(target) = target {
-                        query_ping_readings_for_targets(
-                            server_state
-                                .ping_reading_histories
-                                .get_key_value(&target)
-                                .into_iter(),
-                            &query,
-                            server_state,
-                        )
-                    } else {
-                        query_ping_readings_for_targets(
-                            server_state.ping_reading_histories.iter(),
-                            &query,
-                            server_state,
-                        )
-                    };
-
-                    send_length_prefixed_object_async(
-                        &ServerResponse::PingQueryResult(result),
-                        &mut stream,
-                    )
-                    .await?;
-                }
-                ClientCommand::Disconnect => {
-                    return Ok(());
+        let event = async { receiver.recv().await.ok() }
+            .or(async {
+                (&mut client_closed).await;
+                None
+            })
+            .await;
+
+        let Some(event) = event else {
+            return Ok(());
+        };
+
+        let envelope = Envelope::seal(protocol_version, &ServerResponse::SubscriptionEvent(event))?;
+        with_timeout(write_timeout, "the client to accept events", async {
+            Ok(send_length_prefixed_object_async(&envelope, &mut stream).await?)
+        })
+        .await?;
+    }
+}
+
+fn authenticate(token: &str, server_state: &ServerState) -> Result<(), String> {
+    match &server_state.config().remote {
+        Some(remote_config) if is_valid_token(remote_config, token) => Ok(()),
+        _ => Err(String::from("invalid token")),
+    }
+}
+
+/* Local clients get the access their peer credentials allow, remote clients
+ * are authenticated either by their TLS client certificate or by sending a
+ * valid token before any other command and can only query. `access` is
+ * `None` until a remote client has authenticated.
+ */
+async fn serve_client<S: ClientStream>(
+    mut stream: S,
+    mut access: Option<PeerAccess>,
+    server_state: &Arc<ServerState>,
+) -> anyhow::Result<()> {
+    let query_server_config = server_state.config().query_server;
+    let idle_timeout = query_server_config.idle_timeout();
+    let read_timeout = query_server_config.read_timeout();
+
+    let max_frame_size = query_server_config.max_frame_size;
+
+    let protocol_version = handshake(&mut stream, read_timeout, max_frame_size).await?;
+
+    loop {
+        let envelope: Envelope = receive_length_prefixed_object_async_with_timeouts(
+            &mut stream,
+            idle_timeout,
+            read_timeout,
+            max_frame_size,
+        )
+        .await?;
+
+        /* The whole frame has been read, so a command from a newer client
+         * can be rejected without losing track of the stream.
+         */
+        let command: ClientCommand = match envelope.open(protocol_version) {
+            Ok(command) => command,
+            Err(e) => {
+                send_length_prefixed_object_async(
+                    &Envelope::seal(
+                        protocol_version,
+                        &ServerResponse::Error(ServerError::UnsupportedCommand(e.to_string())),
+                    )?,
+                    &mut stream,
+                )
+                .await?;
+                continue;
+            }
+        };
+
+        let response = match command {
+            ClientCommand::Disconnect => return Ok(()),
+            ClientCommand::Authenticate(token) => {
+                let result = authenticate(&token, server_state);
+                if result.is_ok() {
+                    access = Some(PeerAccess {
+                        read: true,
+                        ..access.unwrap_or_default()
+                    });
                 }
-            },
-            Err(e) => anyhow::bail!(e),
+                ServerResponse::AuthenticationResult(result)
+            }
+            _ if access.is_none() => {
+                ServerResponse::AuthenticationResult(Err(String::from("authentication required")))
+            }
+            command if !access.is_some_and(|access| access.allows(&command)) => {
+                ServerResponse::Error(ServerError::PermissionDenied(String::from(
+                    if command.is_management() {
+                        "managing the service is not allowed"
+                    } else {
+                        "querying the service is not allowed"
+                    },
+                )))
+            }
+            ClientCommand::Subscribe(TargetSubscription { target }) => {
+                return serve_subscription(stream, &target, protocol_version, server_state).await;
+            }
+            /* Queries walk whole histories, so they run on the blocking
+             * thread pool rather than holding up the ping monitors that
+             * share the executor.
+             */
+            command => {
+                let server_state = server_state.clone();
+                smol::unblock(move || respond_to_command(command, &server_state)).await
+            }
+        };
+
+        send_length_prefixed_object_async(
+            &Envelope::seal(protocol_version, &response)?,
+            &mut stream,
+        )
+        .await?;
+
+        if access.is_none() {
+            anyhow::bail!("Client failed to authenticate");
         }
     }
 }
 
-pub async fn serve_query_server(server_state: ServerState) -> anyhow::Result<()> {
-    if server_state.config.remove_existing_socket.unwrap_or(false) {
-        let socket_path = Path::new(crate::UNIX_SOCKET_PATH);
-        let _ = std::fs::remove_file(socket_path);
+/* Taken as soon as a connection is accepted, so that connections still in
+ * their TLS handshake count towards the limit too.
+ */
+fn take_connection_slot(
+    server_state: &ServerState,
+    connection_slots: &Arc<Semaphore>,
+) -> Option<SemaphoreGuardArc> {
+    let connection_slot = connection_slots.try_acquire_arc();
+
+    if connection_slot.is_none() {
+        log::warn!(
+            "Rejecting client, {} connections are already open",
+            server_state.config().query_server.max_connections
+        );
     }
 
-    let listener = UnixListener::bind(crate::UNIX_SOCKET_PATH)?;
+    connection_slot
+}
+
+fn spawn_client<S: ClientStream>(
+    stream: S,
+    access: Option<PeerAccess>,
+    server_state: &Arc<ServerState>,
+    connection_slot: SemaphoreGuardArc,
+) {
+    let server_state = server_state.clone();
+    smol::spawn(async move {
+        server_state
+            .connected_clients
+            .fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = serve_client(stream, access, &server_state).await {
+            log::error!("Encountered error serving client: {e}");
+        }
+        server_state
+            .connected_clients
+            .fetch_sub(1, Ordering::Relaxed);
+        drop(connection_slot);
+    })
+    .detach();
+}
+
+async fn serve_remote_query_server(
+    remote_config: RemoteConfig,
+    server_state: Arc<ServerState>,
+    connection_slots: Arc<Semaphore>,
+) -> anyhow::Result<()> {
+    let acceptor = create_tls_acceptor(&remote_config)?;
+    let listener = TcpListener::bind(&remote_config.listen).await?;
+    log::info!("Listening for remote queries on {}", remote_config.listen);
+
+    let read_timeout = server_state.config().query_server.read_timeout();
 
     let mut incoming = listener.incoming();
 
     while let Some(stream) = incoming.next().await {
         let stream = stream?;
-        if let Err(e) = serve_client(stream, &server_state).await {
-            log::error!("Encountered error serving client: {e}");
-        }
+        let Some(connection_slot) = take_connection_slot(&server_state, &connection_slots) else {
+            continue;
+        };
+        let acceptor = acceptor.clone();
+        let server_state = server_state.clone();
+
+        smol::spawn(async move {
+            let peer = stream.peer_addr();
+            let tls_stream = with_timeout(read_timeout, "the TLS handshake", async {
+                Ok(acceptor.accept(stream).await?)
+            })
+            .await;
+
+            match tls_stream {
+                Ok(tls_stream) => {
+                    let access = tls_stream
+                        .get_ref()
+                        .1
+                        .peer_certificates()
+                        .map(|_| PeerAccess::READ_ONLY);
+                    spawn_client(tls_stream, access, &server_state, connection_slot);
+                }
+                Err(e) => log::warn!("TLS handshake with {peer:?} failed: {e}"),
+            }
+        })
+        .detach();
     }
 
     Ok(())
 }
+
+pub async fn serve_query_server(server_state: Arc<ServerState>) -> anyhow::Result<()> {
+    let socket_path = server_state.config().socket_path();
+
+    if server_state
+        .config()
+        .remove_existing_socket
+        .unwrap_or(false)
+    {
+        let _ = std::fs::remove_file(&socket_path);
+    }
+
+    let listener = bind_socket(&socket_path, &server_state.config())?;
+    log::info!("Listening for queries on {}", socket_path.display());
+
+    let max_connections = server_state.config().query_server.max_connections;
+    let connection_slots = Arc::new(Semaphore::new(max_connections));
+    let serve_local_clients = async {
+        let mut incoming = listener.incoming();
+
+        while let Some(stream) = incoming.next().await {
+            let stream = stream?;
+            let Some(connection_slot) = take_connection_slot(&server_state, &connection_slots)
+            else {
+                continue;
+            };
+
+            let access = match PeerCredentials::of(&stream) {
+                Ok(credentials) => {
+                    log::debug!("Local client connected with {credentials:?}");
+                    PeerAccess::for_local_peer(&credentials, &server_state.config())
+                }
+                Err(e) => {
+                    log::warn!("Could not get the credentials of a local client: {e}");
+                    PeerAccess::default()
+                }
+            };
+
+            spawn_client(stream, Some(access), &server_state, connection_slot);
+        }
+
+        Ok(())
+    };
+
+    match server_state.config().remote.clone() {
+        Some(remote_config) => {
+            serve_local_clients
+                .or(serve_remote_query_server(
+                    remote_config,
+                    server_state.clone(),
+                    connection_slots.clone(),
+                ))
+                .await
+        }
+        None => serve_local_clients.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use smol::io::AsyncWriteExt;
+    use smol::net::unix::UnixStream;
+
+    use super::*;
+    use crate::client::{send_client_command, ServiceAddress};
+
+    fn test_server_state(name: &str) -> ServerState {
+        let directory =
+            std::env::temp_dir().join(format!("oxidenet-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut config = Config::default();
+        config.socket_path = Some(directory.join("oxidenet.sock"));
+        config.remove_existing_socket = Some(true);
+        config.query_server.read_timeout_seconds = 30.0;
+
+        ServerState {
+            monitors: Mutex::new(Monitors::default()),
+            alerts: Mutex::new(AlertEngine::default()),
+            notifier: Mutex::new(
+                Notifier::new(
+                    Default::default(),
+                    HashMap::new(),
+                    directory.join("outbox.json"),
+                )
+                .unwrap(),
+            ),
+            config: RwLock::new(Arc::new(config)),
+            config_path: directory.join("config.toml"),
+            started_at: SystemTime::now(),
+            connected_clients: AtomicUsize::new(0),
+        }
+    }
+
+    #[test]
+    fn stalled_client_does_not_block_other_clients() {
+        let server_state = Arc::new(test_server_state("stalled-client"));
+        let socket_path = server_state.config().socket_path();
+
+        smol::block_on(async {
+            let _server = smol::spawn(serve_query_server(server_state.clone()));
+            while !socket_path.exists() {
+                smol::Timer::after(Duration::from_millis(10)).await;
+            }
+
+            /* Only the first byte of the hello, the rest never arrives */
+            let mut stalled = UnixStream::connect(&socket_path).await.unwrap();
+            stalled.write_all(&[1]).await.unwrap();
+
+            let address = ServiceAddress::Local(socket_path.clone());
+            let response = with_timeout(Duration::from_secs(5), "the other client", async {
+                smol::unblock(move || {
+                    send_client_command(
+                        &address,
+                        ClientCommand::TargetAndStatusQuery(TargetAndStatusQuery { target: None }),
+                    )
+                })
+                .await
+            })
+            .await
+            .unwrap();
+
+            assert!(
+                matches!(response, ServerResponse::StatusResult(statuses) if statuses.is_empty())
+            );
+            drop(stalled);
+        });
+
+        let _ = std::fs::remove_dir_all(socket_path.parent().unwrap());
+    }
+}
@@ -1,55 +1,272 @@
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 
-use crate::ping::PingReadingHistory;
+use async_signal::{Signal, Signals};
+use smol::future::FutureExt;
+use smol::stream::StreamExt;
+use smol::Timer;
+
+use crate::alerts::{validate_alert_rules, AlertEngine};
+use crate::config::Config;
+use crate::health::HEALTH_CHECK_INTERVAL;
+use crate::http::serve_http_api;
+use crate::monitor::Monitors;
+use crate::notify::{deliver_notifications, validate_notifications, Notification, Notifier};
+use crate::seasonal::{load_seasonal_baselines, save_seasonal_baselines, SeasonalSlot};
 use crate::server::{serve_query_server, ServerState};
-use crate::{config::Config, ping::PingMonitor};
 
-async fn run_ping_monitors(ping_monitors: Vec<PingMonitor>) {
-    let ping_monitor_tasks: Vec<smol::Task<(String, anyhow::Result<()>)>> = ping_monitors
-        .into_iter()
-        .map(|mut ping_monitor| {
-            smol::spawn(async move {
-                let target = ping_monitor.target().to_string();
-                if let Err(e) = ping_monitor.watch().await {
-                    (target, Err(e))
-                } else {
-                    (target, Ok(()))
-                }
-            })
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/* Baselines only change as an hour ends, so at most the last hour learned is
+ * lost when the service stops.
+ */
+const SEASONAL_SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/* Identifies a version of the config file, a file replaced by renaming over
+ * it gets a new inode even if its size and modification time match.
+ */
+type ConfigFileVersion = Option<(u64, u64, Option<SystemTime>)>;
+
+async fn config_file_version(config_path: &Path) -> ConfigFileVersion {
+    smol::fs::metadata(config_path)
+        .await
+        .ok()
+        .map(|metadata| (metadata.ino(), metadata.len(), metadata.modified().ok()))
+}
+
+/* Ping monitors, alert rules, access lists and remote tokens are reloaded,
+ * other settings such as the socket, listen addresses or webhooks take effect
+ * when the service is restarted.
+ */
+async fn reload_config(config_path: &Path, server_state: &ServerState) {
+    let mut config = match Config::load(config_path).await {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Keeping the running config: {e}");
+            return;
+        }
+    };
+    config.keep_startup_settings(&server_state.config());
+
+    let applied = validate_alert_rules(&config.alerts).and_then(|()| {
+        server_state
+            .monitors
+            .lock()
+            .unwrap()
+            .apply(config.ping_monitor_configs())
+            .map_err(|e| e.to_string())
+    });
+
+    match applied {
+        Ok(()) => {
+            server_state
+                .alerts
+                .lock()
+                .unwrap()
+                .set_rules(config.alerts.clone());
+            *server_state.config.write().unwrap() = Arc::new(config);
+            log::info!("Reloaded the config from {}", config_path.display());
+        }
+        Err(e) => log::error!(
+            "Keeping the running config, {} is invalid: {e}",
+            config_path.display()
+        ),
+    }
+}
+
+/* Reloads on SIGHUP, and when the config file changes on disk */
+async fn watch_config(config_path: PathBuf, server_state: Arc<ServerState>) -> anyhow::Result<()> {
+    let mut signals = Signals::new([Signal::Hup])?;
+    let mut version = config_file_version(&config_path).await;
+
+    loop {
+        let hangup = async {
+            signals.next().await;
+            true
+        }
+        .or(async {
+            Timer::after(CONFIG_POLL_INTERVAL).await;
+            false
         })
-        .collect();
+        .await;
 
-    for ping_monitor_task in ping_monitor_tasks {
-        let (target, maybe_error) = ping_monitor_task.await;
-        match maybe_error {
-            Ok(_) => log::error!("Ping monitor task for {target} stopped unexpectedly"),
-            Err(e) => {
-                log::error!("Ping monitor task for {target} stopped unexpectedly with error: {e}",)
-            }
+        let current_version = config_file_version(&config_path).await;
+
+        if hangup {
+            log::info!("Received SIGHUP, reloading {}", config_path.display());
+        } else if current_version != version && current_version.is_some() {
+            log::info!("{} changed, reloading it", config_path.display());
+        } else {
+            continue;
         }
+
+        version = current_version;
+        reload_config(&config_path, &server_state).await;
     }
 }
 
-pub async fn run_service(config: Config) -> anyhow::Result<()> {
-    let ping_monitors = config.ping_monitors();
+/* Alert rules are evaluated along with the health of every target, and both
+ * notify when they change.
+ */
+async fn check_health(server_state: Arc<ServerState>) {
+    loop {
+        Timer::after(HEALTH_CHECK_INTERVAL).await;
+        let now = SystemTime::now();
 
-    let reading_histories: HashMap<String, Arc<Mutex<PingReadingHistory>>> = ping_monitors
-        .iter()
-        .map(|monitor| (monitor.target().to_string(), monitor.reading_history()))
-        .collect();
+        let (state_changes, targets) = {
+            let mut monitors = server_state.monitors.lock().unwrap();
+            (monitors.update_health(now), monitors.select(&None))
+        };
+
+        let alert_events = match targets {
+            Ok(targets) => server_state.alerts.lock().unwrap().evaluate(&targets, now),
+            Err(_) => vec![],
+        };
 
-    let server_state = ServerState {
-        ping_reading_histories: reading_histories,
-        config,
+        let notifications = state_changes
+            .iter()
+            .filter_map(Notification::from_state_change)
+            .chain(alert_events.iter().map(Notification::from_alert));
+
+        let mut notifier = server_state.notifier.lock().unwrap();
+        for notification in notifications {
+            notifier.notify(&notification);
+        }
+    }
+}
+
+fn restore_seasonal_baselines(monitors: &Monitors, path: &Path) {
+    let baselines = match load_seasonal_baselines(path) {
+        Ok(baselines) => baselines,
+        Err(e) => {
+            log::warn!("Starting without the learned usual latencies: {e}");
+            return;
+        }
     };
 
-    let run_ping_monitors_task = smol::spawn(run_ping_monitors(ping_monitors));
+    for selected in monitors.select(&None).unwrap_or_default() {
+        if let Some(slots) = baselines.get(&selected.target) {
+            selected
+                .reading_history
+                .lock()
+                .unwrap()
+                .seasonal_baseline_mut()
+                .restore(slots);
+        }
+    }
+}
+
+async fn save_learned_baselines(server_state: &ServerState, path: &Path) {
+    let targets = server_state
+        .monitors
+        .lock()
+        .unwrap()
+        .select(&None)
+        .unwrap_or_default();
+    let baselines: HashMap<String, Vec<SeasonalSlot>> = targets
+        .into_iter()
+        .map(|selected| {
+            let history = selected.reading_history.lock().unwrap();
+            (
+                selected.target,
+                history.seasonal_baseline().slots().to_vec(),
+            )
+        })
+        .collect();
 
-    let serve_query_server_task = smol::spawn(serve_query_server(server_state));
+    let path = path.to_path_buf();
+    if let Err(e) = smol::unblock(move || save_seasonal_baselines(&path, &baselines)).await {
+        log::warn!("Could not save the learned usual latencies: {e}");
+    }
+}
 
-    serve_query_server_task.await?;
-    run_ping_monitors_task.await;
+async fn save_seasonal_baselines_periodically(server_state: Arc<ServerState>) {
+    let path = server_state.config().seasonal_baselines_path();
 
+    loop {
+        Timer::after(SEASONAL_SAVE_INTERVAL).await;
+        save_learned_baselines(&server_state, &path).await;
+    }
+}
+
+/* Returns once the service is asked to stop */
+async fn stop_requested() -> anyhow::Result<()> {
+    let mut signals = Signals::new([Signal::Term, Signal::Int])?;
+
+    if let Some(Ok(signal)) = signals.next().await {
+        log::info!("Received {signal:?}, stopping");
+    }
     Ok(())
 }
+
+pub async fn run_service(config: Config, config_path: PathBuf) -> anyhow::Result<()> {
+    config
+        .query_server
+        .validate()
+        .map_err(|e| anyhow::anyhow!("query-server: {e}"))?;
+
+    let mut monitors = Monitors::default();
+    monitors.apply(config.ping_monitor_configs())?;
+    restore_seasonal_baselines(&monitors, &config.seasonal_baselines_path());
+
+    validate_alert_rules(&config.alerts).map_err(anyhow::Error::msg)?;
+    let mut alerts = AlertEngine::default();
+    alerts.set_rules(config.alerts.clone());
+
+    validate_notifications(&config.notifications, &config.webhooks).map_err(anyhow::Error::msg)?;
+    let notifier = Notifier::new(
+        config.notifications.clone(),
+        config.webhooks.clone(),
+        config.outbox_path(),
+    )?;
+
+    let server_state = Arc::new(ServerState {
+        monitors: Mutex::new(monitors),
+        alerts: Mutex::new(alerts),
+        notifier: Mutex::new(notifier),
+        config: RwLock::new(Arc::new(config)),
+        config_path: config_path.clone(),
+        started_at: SystemTime::now(),
+        connected_clients: AtomicUsize::new(0),
+    });
+
+    if let Some(http_config) = server_state.config().http.clone() {
+        let server_state = server_state.clone();
+        smol::spawn(async move {
+            if let Err(e) = serve_http_api(http_config, server_state).await {
+                log::error!("HTTP API stopped with error: {e}");
+            }
+        })
+        .detach();
+    }
+
+    smol::spawn(check_health(server_state.clone())).detach();
+    smol::spawn(deliver_notifications(server_state.clone())).detach();
+    smol::spawn(save_seasonal_baselines_periodically(server_state.clone())).detach();
+
+    {
+        let server_state = server_state.clone();
+        smol::spawn(async move {
+            if let Err(e) = watch_config(config_path, server_state).await {
+                log::error!("Config reloading stopped with error: {e}");
+            }
+        })
+        .detach();
+    }
+
+    /* Whatever was learned since the last hourly save is kept */
+    let stopped = serve_query_server(server_state.clone())
+        .or(stop_requested())
+        .await;
+    save_learned_baselines(
+        &server_state,
+        &server_state.config().seasonal_baselines_path(),
+    )
+    .await;
+
+    stopped
+}
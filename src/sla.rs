@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, NaiveTime, TimeDelta, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::ping::PingReading;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct SlaConfig {
+    #[serde(rename = "latency-threshold-ms")]
+    pub latency_threshold_ms: f32,
+    #[serde(rename = "interval-seconds", default = "default_sla_interval_seconds")]
+    pub interval_seconds: f32,
+}
+
+fn default_sla_interval_seconds() -> f32 {
+    60.0
+}
+
+impl Default for SlaConfig {
+    fn default() -> Self {
+        SlaConfig {
+            latency_threshold_ms: 100.0,
+            interval_seconds: default_sla_interval_seconds(),
+        }
+    }
+}
+
+impl SlaConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if Duration::try_from_secs_f32(self.latency_threshold_ms / 1000.0).is_err() {
+            return Err(format!(
+                "sla latency threshold must be a non-negative number of ms, not {}",
+                self.latency_threshold_ms
+            ));
+        }
+
+        /* Reports walk every interval of a period, so they are kept to at
+         * least a second.
+         */
+        if self.interval_seconds.is_nan()
+            || self.interval_seconds < 1.0
+            || Duration::try_from_secs_f32(self.interval_seconds).is_err()
+        {
+            return Err(format!(
+                "sla interval must be at least 1 second, not {}",
+                self.interval_seconds
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn latency_threshold(&self) -> Duration {
+        Duration::try_from_secs_f32(self.latency_threshold_ms / 1000.0).unwrap_or(Duration::MAX)
+    }
+
+    fn interval_length(&self) -> Duration {
+        Duration::try_from_secs_f32(self.interval_seconds).unwrap_or(Duration::MAX)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum SlaPeriod {
+    Day,
+    Week,
+    Month,
+}
+
+impl SlaPeriod {
+    /* Calendar periods start at local midnight, weeks start on monday */
+    fn start_of(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            SlaPeriod::Day => date,
+            SlaPeriod::Week => date
+                .checked_sub_days(Days::new(date.weekday().num_days_from_monday().into()))
+                .unwrap_or(date),
+            SlaPeriod::Month => date.with_day(1).unwrap_or(date),
+        }
+    }
+}
+
+/* Local midnight, or the earliest time that exists on days where a DST change
+ * skips it.
+ */
+fn start_of_day(date: NaiveDate) -> SystemTime {
+    let midnight = date.and_time(NaiveTime::MIN);
+
+    (0..24 * 60)
+        .map(|minutes| midnight + TimeDelta::minutes(minutes))
+        .find_map(|time| Local.from_local_datetime(&time).earliest())
+        .map_or_else(|| midnight.and_utc().into(), SystemTime::from)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SlaPeriodReport {
+    pub period_start: SystemTime,
+    pub total_intervals: usize,
+    pub good_intervals: usize,
+    pub availability_percent: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SlaReport {
+    pub config: SlaConfig,
+    pub period: SlaPeriod,
+    pub periods: Vec<SlaPeriodReport>,
+}
+
+#[derive(Default)]
+struct IntervalState {
+    has_readings: bool,
+    over_threshold: bool,
+    lost_packets: bool,
+}
+
+impl SlaReport {
+    /* Splits the readings into fixed intervals, an interval is good if it has
+     * readings, all of them under the latency threshold, and no lost packets.
+     * Intervals without any readings between the first and last reading are
+     * counted as bad, as every packet in them was lost.
+     */
+    pub fn from_readings(
+        readings: &[&PingReading],
+        config: SlaConfig,
+        period: SlaPeriod,
+    ) -> SlaReport {
+        let interval_length = config.interval_length();
+        let latency_threshold = config.latency_threshold();
+
+        let interval_index = |time: SystemTime| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+                / interval_length.as_nanos()
+        };
+
+        let mut intervals: BTreeMap<u128, IntervalState> = BTreeMap::new();
+        let mut previous_sequence = None;
+
+        for reading in readings {
+            let interval = intervals
+                .entry(interval_index(reading.timestamp))
+                .or_default();
+
+            interval.has_readings = true;
+            interval.over_threshold |= reading.latency > latency_threshold;
+
+            if let (Some(previous), Some(current)) = (previous_sequence, reading.sequence) {
+                interval.lost_packets |= current > previous + 1;
+            }
+            previous_sequence = reading.sequence;
+        }
+
+        let mut periods: BTreeMap<NaiveDate, (usize, usize)> = BTreeMap::new();
+
+        if let (Some(first), Some(last)) = (
+            intervals.first_key_value().map(|(index, _)| *index),
+            intervals.last_key_value().map(|(index, _)| *index),
+        ) {
+            for index in first..=last {
+                let good = intervals.get(&index).is_some_and(|interval| {
+                    interval.has_readings && !interval.over_threshold && !interval.lost_packets
+                });
+
+                let interval_start = SystemTime::UNIX_EPOCH
+                    + Duration::from_nanos((index * interval_length.as_nanos()) as u64);
+                let date = DateTime::<Local>::from(interval_start).date_naive();
+
+                let (total, good_count) = periods.entry(period.start_of(date)).or_default();
+                *total += 1;
+                if good {
+                    *good_count += 1;
+                }
+            }
+        }
+
+        SlaReport {
+            config,
+            period,
+            periods: periods
+                .into_iter()
+                .map(
+                    |(period_start, (total_intervals, good_intervals))| SlaPeriodReport {
+                        period_start: start_of_day(period_start),
+                        total_intervals,
+                        good_intervals,
+                        availability_percent: good_intervals as f64 / total_intervals as f64
+                            * 100.0,
+                    },
+                )
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ping::PingReading;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub struct TimeRange {
+    pub start: Option<SystemTime>,
+    pub end: Option<SystemTime>,
+}
+
+impl TimeRange {
+    /* Builds a range relative to the current time, `since` and `until` are
+     * how long ago the range starts and ends respectively.
+     */
+    pub fn relative_to_now(since: Option<Duration>, until: Option<Duration>) -> TimeRange {
+        let now = SystemTime::now();
+
+        TimeRange {
+            start: since.and_then(|since| now.checked_sub(since)),
+            end: until.and_then(|until| now.checked_sub(until)),
+        }
+    }
+
+    pub fn contains(&self, time: SystemTime) -> bool {
+        self.start.is_none_or(|start| time >= start) && self.end.is_none_or(|end| time <= end)
+    }
+
+    pub fn filter<'a, I: IntoIterator<Item = &'a PingReading>>(
+        &self,
+        readings: I,
+    ) -> Vec<&'a PingReading> {
+        readings
+            .into_iter()
+            .filter(|reading| self.contains(reading.timestamp))
+            .collect()
+    }
+
+    /* Compares the range against the oldest retained reading, readings older
+     * than that have been dropped from the history.
+     */
+    pub fn coverage(&self, history_start: Option<SystemTime>) -> HistoryCoverage {
+        let Some(history_start) = history_start else {
+            return HistoryCoverage::OutsideHistory;
+        };
+
+        if self.end.is_some_and(|end| end < history_start) {
+            HistoryCoverage::OutsideHistory
+        } else if self.start.is_some_and(|start| start < history_start) {
+            HistoryCoverage::Partial { history_start }
+        } else {
+            HistoryCoverage::Complete
+        }
+    }
+
+    pub fn shifted_back(&self, offset: Duration) -> TimeRange {
+        TimeRange {
+            start: self.start.and_then(|start| start.checked_sub(offset)),
+            end: self.end.and_then(|end| end.checked_sub(offset)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum HistoryCoverage {
+    Complete,
+    Partial { history_start: SystemTime },
+    OutsideHistory,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct PingStatistics {
+    pub count: usize,
+    pub min_ms: f64,
+    pub max_ms: f64,
+    pub mean_ms: f64,
+    pub median_ms: f64,
+    pub p90_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub std_dev_ms: f64,
+    pub jitter_ms: f64,
+    pub lost: u64,
+    pub loss_percent: f64,
+}
+
+fn duration_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/* Nearest-rank percentile over already sorted values */
+pub fn percentile(sorted_values: &[f64], percent: f64) -> f64 {
+    if sorted_values.is_empty() {
+        return 0.0;
+    }
+
+    let rank = (percent / 100.0 * sorted_values.len() as f64).ceil() as usize;
+
+    sorted_values[rank.clamp(1, sorted_values.len()) - 1]
+}
+
+/* Counts the packets missing between consecutive readings using their
+ * sequence numbers, a sequence number going backwards means the ping
+ * process was restarted so the gap is not counted as loss.
+ */
+pub fn count_lost_packets(readings: &[&PingReading]) -> u64 {
+    readings
+        .windows(2)
+        .filter_map(|pair| match (pair[0].sequence, pair[1].sequence) {
+            (Some(previous), Some(current)) if current > previous => Some(current - previous - 1),
+            _ => None,
+        })
+        .sum()
+}
+
+impl PingStatistics {
+    pub fn from_readings(readings: &[&PingReading]) -> Option<PingStatistics> {
+        if readings.is_empty() {
+            return None;
+        }
+
+        let latencies: Vec<f64> = readings
+            .iter()
+            .map(|reading| duration_ms(reading.latency))
+            .collect();
+
+        let mut sorted_latencies = latencies.clone();
+        sorted_latencies.sort_by(f64::total_cmp);
+
+        let count = latencies.len();
+        let mean_ms = latencies.iter().sum::<f64>() / count as f64;
+        let variance = latencies
+            .iter()
+            .map(|latency| (latency - mean_ms).powi(2))
+            .sum::<f64>()
+            / count as f64;
+
+        let jitter_ms = if count > 1 {
+            latencies
+                .windows(2)
+                .map(|pair| (pair[1] - pair[0]).abs())
+                .sum::<f64>()
+                / (count - 1) as f64
+        } else {
+            0.0
+        };
+
+        let lost = count_lost_packets(readings);
+        let loss_percent = lost as f64 / (lost as f64 + count as f64) * 100.0;
+
+        Some(PingStatistics {
+            count,
+            min_ms: sorted_latencies[0],
+            max_ms: sorted_latencies[count - 1],
+            mean_ms,
+            median_ms: percentile(&sorted_latencies, 50.0),
+            p90_ms: percentile(&sorted_latencies, 90.0),
+            p95_ms: percentile(&sorted_latencies, 95.0),
+            p99_ms: percentile(&sorted_latencies, 99.0),
+            std_dev_ms: variance.sqrt(),
+            jitter_ms,
+            lost,
+            loss_percent,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct PeriodStatistics {
+    pub range: TimeRange,
+    pub coverage: HistoryCoverage,
+    pub statistics: Option<PingStatistics>,
+}
+
+impl PeriodStatistics {
+    pub fn from_readings(
+        range: TimeRange,
+        readings: &[&PingReading],
+        history_start: Option<SystemTime>,
+    ) -> PeriodStatistics {
+        PeriodStatistics {
+            range,
+            coverage: range.coverage(history_start),
+            statistics: PingStatistics::from_readings(&range.filter(readings.iter().copied())),
+        }
+    }
+}
+
+/* Differences are the second period minus the first */
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct StatisticsDifference {
+    pub mean_ms: f64,
+    pub median_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub std_dev_ms: f64,
+    pub jitter_ms: f64,
+    pub loss_percent: f64,
+}
+
+impl StatisticsDifference {
+    pub fn between(first: &PingStatistics, second: &PingStatistics) -> StatisticsDifference {
+        StatisticsDifference {
+            mean_ms: second.mean_ms - first.mean_ms,
+            median_ms: second.median_ms - first.median_ms,
+            p95_ms: second.p95_ms - first.p95_ms,
+            p99_ms: second.p99_ms - first.p99_ms,
+            std_dev_ms: second.std_dev_ms - first.std_dev_ms,
+            jitter_ms: second.jitter_ms - first.jitter_ms,
+            loss_percent: second.loss_percent - first.loss_percent,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct PeriodComparison {
+    pub first: PeriodStatistics,
+    pub second: PeriodStatistics,
+    pub difference: Option<StatisticsDifference>,
+}
+
+impl PeriodComparison {
+    /* A difference is only given when both periods are fully covered by the
+     * history, comparing against partial data would be misleading.
+     */
+    pub fn new(first: PeriodStatistics, second: PeriodStatistics) -> PeriodComparison {
+        let difference = match (&first, &second) {
+            (
+                PeriodStatistics {
+                    coverage: HistoryCoverage::Complete,
+                    statistics: Some(first_statistics),
+                    ..
+                },
+                PeriodStatistics {
+                    coverage: HistoryCoverage::Complete,
+                    statistics: Some(second_statistics),
+                    ..
+                },
+            ) => Some(StatisticsDifference::between(
+                first_statistics,
+                second_statistics,
+            )),
+            _ => None,
+        };
+
+        PeriodComparison {
+            first,
+            second,
+            difference,
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use smol::channel::{Sender, TrySendError};
+
+use crate::ping::PingReading;
+
+/* Events are buffered per subscription, when a client falls behind events are
+ * dropped rather than slowing down the monitors, and the client is told how
+ * many it missed once there is room again.
+ */
+pub const SUBSCRIPTION_BUFFER_SIZE: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SubscriptionEvent {
+    Reading {
+        target: String,
+        reading: PingReading,
+    },
+    Lagged {
+        target: String,
+        missed: u64,
+    },
+}
+
+#[derive(Debug)]
+pub struct Subscriber {
+    target: String,
+    sender: Sender<SubscriptionEvent>,
+    missed: u64,
+}
+
+impl Subscriber {
+    pub fn new(target: String, sender: Sender<SubscriptionEvent>) -> Subscriber {
+        Subscriber {
+            target,
+            sender,
+            missed: 0,
+        }
+    }
+
+    fn try_send(&mut self, event: SubscriptionEvent) -> bool {
+        match self.sender.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.missed += 1;
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /* Returns false once the subscription has been closed and the subscriber
+     * can be dropped.
+     */
+    pub fn notify(&mut self, reading: &PingReading) -> bool {
+        if self.missed > 0 {
+            let lagged = SubscriptionEvent::Lagged {
+                target: self.target.clone(),
+                missed: self.missed,
+            };
+
+            match self.sender.try_send(lagged) {
+                Ok(()) => self.missed = 0,
+                Err(TrySendError::Full(_)) => {
+                    self.missed += 1;
+                    return true;
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+        }
+
+        self.try_send(SubscriptionEvent::Reading {
+            target: self.target.clone(),
+            reading: reading.clone(),
+        })
+    }
+}
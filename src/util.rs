@@ -1,64 +1,317 @@
+use bincode::Options;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use smol::future::{Future, FutureExt};
 use smol::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use smol::Timer;
 use std::io::{Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+pub async fn with_timeout<T, F: Future<Output = anyhow::Result<T>>>(
+    timeout: Duration,
+    waiting_for: &str,
+    future: F,
+) -> anyhow::Result<T> {
+    future
+        .or(async {
+            Timer::after(timeout).await;
+            Err(anyhow::anyhow!(
+                "Timed out after {timeout:?} waiting for {waiting_for}"
+            ))
+        })
+        .await
+}
+
+/* Frames are a little endian u64 length followed by that many bytes of
+ * bincode. The length is checked against a limit before anything is
+ * allocated, and bincode is limited to the frame so that lengths inside the
+ * frame cannot claim more than was received either.
+ */
+#[derive(thiserror::Error, Debug)]
+pub enum FrameError {
+    #[error("frame of {size} bytes is larger than the limit of {max_size} bytes")]
+    TooLarge { size: u64, max_size: u64 },
+    #[error("timed out waiting for {waiting_for}")]
+    TimedOut { waiting_for: &'static str },
+    #[error("connection closed")]
+    Closed,
+    #[error("could not encode frame: {0}")]
+    Encode(bincode::Error),
+    #[error("could not decode frame: {0}")]
+    Decode(bincode::Error),
+    #[error(transparent)]
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => FrameError::Closed,
+            _ => FrameError::Io(e),
+        }
+    }
+}
+
+/* The same encoding as `bincode::serialize`, with a limit on how much can
+ * be read.
+ */
+pub fn bincode_options(limit: u64) -> impl bincode::Options {
+    bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+}
+
+pub fn decode_with_limit<T: DeserializeOwned>(bytes: &[u8], limit: u64) -> bincode::Result<T> {
+    bincode_options(limit).deserialize(bytes)
+}
+
+fn check_frame_size(size_buf: [u8; 8], max_size: u64) -> Result<usize, FrameError> {
+    let size = u64::from_le_bytes(size_buf);
+
+    match usize::try_from(size) {
+        Ok(frame_size) if size <= max_size => Ok(frame_size),
+        _ => Err(FrameError::TooLarge { size, max_size }),
+    }
+}
+
+fn encode_frame<T: Serialize>(obj: &T) -> Result<Vec<u8>, FrameError> {
+    let obj_bytes = bincode::serialize(obj).map_err(FrameError::Encode)?;
+
+    let mut frame = Vec::with_capacity(std::mem::size_of::<u64>() + obj_bytes.len());
+    frame.extend_from_slice(&(obj_bytes.len() as u64).to_le_bytes());
+    frame.extend_from_slice(&obj_bytes);
+
+    Ok(frame)
+}
+
+async fn with_frame_timeout<T, F: Future<Output = Result<T, FrameError>>>(
+    timeout: Duration,
+    waiting_for: &'static str,
+    future: F,
+) -> Result<T, FrameError> {
+    future
+        .or(async {
+            Timer::after(timeout).await;
+            Err(FrameError::TimedOut { waiting_for })
+        })
+        .await
+}
 
 pub async fn send_length_prefixed_object_async<T: Serialize, W: AsyncWrite + Unpin>(
     obj: &T,
     w: &mut W,
-) -> anyhow::Result<()> {
-    let obj_size: u64 = bincode::serialized_size(&obj)?;
-
-    w.write_all(&obj_size.to_le_bytes()).await?;
-
-    let obj_bytes = bincode::serialize(obj)?;
-    w.write_all(&obj_bytes).await?;
+) -> Result<(), FrameError> {
+    w.write_all(&encode_frame(obj)?).await?;
 
     Ok(())
 }
 
-pub async fn receive_length_prefixed_object_async<T: DeserializeOwned, R: AsyncRead + Unpin>(
+/* The idle timeout applies until the first byte of the object arrives, the
+ * read timeout then applies to receiving the rest of it.
+ */
+pub async fn receive_length_prefixed_object_async_with_timeouts<
+    T: DeserializeOwned,
+    R: AsyncRead + Unpin,
+>(
     r: &mut R,
-) -> anyhow::Result<T> {
+    idle_timeout: Duration,
+    read_timeout: Duration,
+    max_frame_size: u64,
+) -> Result<T, FrameError> {
     let mut size_buf = [0; std::mem::size_of::<u64>()];
 
-    r.read_exact(&mut size_buf).await?;
+    with_frame_timeout(idle_timeout, "the next object", async {
+        Ok(r.read_exact(&mut size_buf[..1]).await?)
+    })
+    .await?;
 
-    let size = u64::from_le_bytes(size_buf);
+    with_frame_timeout(read_timeout, "the rest of the object", async {
+        r.read_exact(&mut size_buf[1..]).await?;
 
-    let mut buf = vec![0; size.try_into().expect("u64 to fit into usize")];
+        let mut buf = vec![0; check_frame_size(size_buf, max_frame_size)?];
 
-    r.read_exact(&mut buf).await?;
+        r.read_exact(&mut buf).await?;
 
-    Ok(bincode::deserialize(&buf)?)
+        decode_with_limit(&buf, max_frame_size).map_err(FrameError::Decode)
+    })
+    .await
+}
+
+/* Deadlines for the blocking helpers are the read timeout of the underlying
+ * socket, which surface here as `WouldBlock` or `TimedOut`.
+ */
+fn map_blocking_read_error(e: std::io::Error) -> FrameError {
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => FrameError::TimedOut {
+            waiting_for: "the service to respond",
+        },
+        _ => e.into(),
+    }
 }
 
 pub fn send_length_prefixed_object<T: Serialize, W: Write>(
     obj: &T,
     w: &mut W,
-) -> anyhow::Result<()> {
-    let obj_size: u64 = bincode::serialized_size(&obj)?;
-
-    w.write_all(&obj_size.to_le_bytes())?;
-
-    let obj_bytes = bincode::serialize(obj)?;
-    w.write_all(&obj_bytes)?;
+) -> Result<(), FrameError> {
+    w.write_all(&encode_frame(obj)?)?;
 
     Ok(())
 }
 
 pub fn receive_length_prefixed_object<T: DeserializeOwned, R: Read>(
     r: &mut R,
-) -> anyhow::Result<T> {
+    max_frame_size: u64,
+) -> Result<T, FrameError> {
     let mut size_buf = [0; std::mem::size_of::<u64>()];
 
-    r.read_exact(&mut size_buf)?;
+    r.read_exact(&mut size_buf)
+        .map_err(map_blocking_read_error)?;
 
-    let size = u64::from_le_bytes(size_buf);
+    let mut buf = vec![0; check_frame_size(size_buf, max_frame_size)?];
+
+    r.read_exact(&mut buf).map_err(map_blocking_read_error)?;
+
+    decode_with_limit(&buf, max_frame_size).map_err(FrameError::Decode)
+}
+
+/* State the service keeps across restarts is JSON, written to a temporary
+ * file first and renamed over the old one so that a crash while writing
+ * leaves the previous contents.
+ */
+pub fn write_state_file(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let temporary_path = path.with_extension("json.tmp");
+    std::fs::write(&temporary_path, contents)?;
+    std::fs::rename(&temporary_path, path)?;
+
+    Ok(())
+}
+
+/* A missing file is an empty one, `name` says what it holds in errors */
+pub fn read_state_file<T: DeserializeOwned + Default>(
+    path: &Path,
+    name: &str,
+) -> anyhow::Result<T> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Invalid {name} {}: {e}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(anyhow::anyhow!(
+            "Could not read {name} {}: {e}",
+            path.display()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::client::ClientCommand;
+    use crate::protocol::{Envelope, Hello, PROTOCOL_VERSION};
+
+    const MAX_FRAME_SIZE: u64 = 1024;
+
+    fn frame_of(bytes: &[u8]) -> Vec<u8> {
+        let mut frame = (bytes.len() as u64).to_le_bytes().to_vec();
+        frame.extend_from_slice(bytes);
+        frame
+    }
+
+    fn receive<T: DeserializeOwned>(frame: &[u8]) -> Result<T, FrameError> {
+        receive_length_prefixed_object(&mut Cursor::new(frame), MAX_FRAME_SIZE)
+    }
+
+    fn receive_async<T: DeserializeOwned>(frame: &[u8]) -> Result<T, FrameError> {
+        smol::block_on(receive_length_prefixed_object_async_with_timeouts(
+            &mut smol::io::Cursor::new(frame),
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            MAX_FRAME_SIZE,
+        ))
+    }
+
+    /* Deterministic, so that failures can be reproduced */
+    fn xorshift(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected_before_reading_them() {
+        for size in [MAX_FRAME_SIZE + 1, u32::MAX as u64, u64::MAX] {
+            let frame = size.to_le_bytes();
+
+            assert!(matches!(
+                receive::<Envelope>(&frame),
+                Err(FrameError::TooLarge { .. })
+            ));
+            assert!(matches!(
+                receive_async::<Envelope>(&frame),
+                Err(FrameError::TooLarge { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let frame = encode_frame(&Hello::new(&["episodes", "statistics"])).unwrap();
+        assert!(receive::<Hello>(&frame).is_ok());
+
+        for length in 0..frame.len() {
+            assert!(receive::<Hello>(&frame[..length]).is_err());
+            assert!(receive_async::<Hello>(&frame[..length]).is_err());
+        }
+    }
+
+    /* Lengths inside the frame are limited by the frame, so a short frame
+     * cannot make the decoder allocate a huge buffer.
+     */
+    #[test]
+    fn lengths_inside_frames_cannot_exceed_them() {
+        let mut bytes = PROTOCOL_VERSION.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+
+        assert!(matches!(
+            receive::<Envelope>(&frame_of(&bytes)),
+            Err(FrameError::Decode(_))
+        ));
+
+        let envelope = Envelope {
+            protocol_version: PROTOCOL_VERSION,
+            payload: [0u8; 4]
+                .into_iter()
+                .chain((1u64 << 40).to_le_bytes())
+                .collect(),
+        };
+        assert!(envelope.open::<ClientCommand>(PROTOCOL_VERSION).is_err());
+    }
 
-    let mut buf = vec![0; size.try_into().expect("u64 to fit into usize")];
+    #[test]
+    fn random_frames_do_not_panic() {
+        let mut state = 0x2545_f491_4f6c_dd1d;
 
-    r.read_exact(&mut buf)?;
+        for _ in 0..100_000 {
+            let length = xorshift(&mut state) % 64;
+            let bytes: Vec<u8> = (0..length).map(|_| xorshift(&mut state) as u8).collect();
 
-    Ok(bincode::deserialize(&buf)?)
+            /* Both well formed frames of random bytes and random bytes where
+             * the length should be.
+             */
+            if let Ok(envelope) = receive::<Envelope>(&frame_of(&bytes)) {
+                assert!(envelope.payload.len() <= bytes.len());
+                let _ = envelope.open::<ClientCommand>(envelope.protocol_version);
+            }
+            let _ = receive::<Envelope>(&bytes);
+            let _ = receive_async::<ClientCommand>(&frame_of(&bytes));
+        }
+    }
 }